@@ -0,0 +1,120 @@
+use crate::{EntityId, World};
+use std::sync::Mutex;
+
+type Command = Box<dyn FnOnce(&mut World) + Send>;
+
+/// A set of components that can be added to an entity in one go
+pub trait Bundle: Send + 'static {
+    fn insert_into(self, world: &mut World, entity: EntityId);
+}
+
+macro_rules! impl_bundle_tuple {
+    ($($name:ident),*) => {
+        #[allow(non_snake_case)]
+        impl<$($name: Send + Sync + 'static),*> Bundle for ($($name,)*) {
+            fn insert_into(self, world: &mut World, entity: EntityId) {
+                let ($($name,)*) = self;
+                $(world.add_component_to_entity(entity, $name);)*
+            }
+        }
+    };
+}
+
+impl_bundle_tuple!(A);
+impl_bundle_tuple!(A, B);
+impl_bundle_tuple!(A, B, C);
+impl_bundle_tuple!(A, B, C, D);
+impl_bundle_tuple!(A, B, C, D, E);
+impl_bundle_tuple!(A, B, C, D, E, F);
+
+/// Queue of world changes made from inside systems.
+///
+/// Systems only get `&World` and usually hold component borrows, so spawning or
+/// despawning is queued here and applied by the schedule at the end of each stage.
+pub struct Commands {
+    queue: Mutex<Vec<Command>>,
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        Self { queue: Mutex::new(Vec::new()) }
+    }
+
+    /// Queues an arbitrary change to the world
+    pub fn add(&self, command: impl FnOnce(&mut World) + Send + 'static) {
+        self.queue.lock().unwrap().push(Box::new(command));
+    }
+
+    /// Queues a new entity made of the given components
+    pub fn spawn(&self, bundle: impl Bundle) {
+        self.add(move |world| {
+            let entity = world.new_entity();
+            bundle.insert_into(world, entity);
+        });
+    }
+
+    /// Queues a new entity spawned from a prefab
+    pub fn spawn_prefab(&self, name: &str, at: (f64, f64)) {
+        let name = name.to_string();
+        self.add(move |world| {
+            world.spawn_prefab(&name, at);
+        });
+    }
+
+    pub fn insert<T: Send + Sync + 'static>(&self, entity: EntityId, component: T) {
+        self.add(move |world| world.add_component_to_entity(entity, component));
+    }
+
+    pub fn remove<T: 'static>(&self, entity: EntityId) {
+        self.add(move |world| {
+            world.remove_component_from_entity::<T>(entity);
+        });
+    }
+
+    pub fn despawn(&self, entity: EntityId) {
+        self.add(move |world| {
+            world.despawn(entity);
+        });
+    }
+
+    /// Takes every queued command, in the order they were queued
+    pub fn drain(&self) -> Vec<Command> {
+        std::mem::take(&mut *self.queue.lock().unwrap())
+    }
+}
+
+impl Default for Commands {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Coordinates;
+
+    #[test]
+    fn applies_in_order_including_commands_queued_while_applying() {
+        let mut world = World::new();
+        let kept = world.new_entity();
+        let gone = world.new_entity();
+        world.add_component_to_entity(kept, 1u8);
+
+        world.commands().spawn((Coordinates { coord_x: 3.0, coord_y: 0.0 }, 2u8));
+        world.commands().insert(kept, Coordinates { coord_x: 1.0, coord_y: 0.0 });
+        world.commands().remove::<u8>(kept);
+        world.commands().despawn(gone);
+        world.commands().add(|world| world.commands().spawn((5u8,)));
+        assert!(world.is_alive(gone));
+
+        world.apply_commands();
+        assert!(!world.is_alive(gone));
+        assert!(world.query::<(&u8,)>().get(kept).is_none());
+        assert_eq!(world.query::<(&Coordinates,)>().iter().count(), 2);
+        let mut bytes: Vec<u8> = world.query::<(&u8,)>().iter().map(|(_, (byte,))| *byte).collect();
+        bytes.sort();
+        assert_eq!(bytes, [2, 5]);
+        assert!(world.commands().drain().is_empty());
+    }
+}
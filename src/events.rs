@@ -0,0 +1,71 @@
+use std::sync::Mutex;
+
+pub trait EventQueue: Send + Sync {
+    fn as_any(&self) -> &dyn std::any::Any;
+    fn clear_before(&mut self, tick: u64);
+}
+
+/// Every event of one type still being kept, stamped with the tick it was sent at.
+///
+/// Events stay around for two runs of the schedule, so every system gets to
+/// read them once no matter which stage sent them.
+pub struct Events<E> {
+    queue: Mutex<Vec<(u64, E)>>,
+}
+
+impl<E> Events<E> {
+    pub fn new() -> Self {
+        Self { queue: Mutex::new(Vec::new()) }
+    }
+
+    pub fn send(&self, tick: u64, event: E) {
+        self.queue.lock().unwrap().push((tick, event));
+    }
+
+    /// Events sent after `after` and up to and including `until`
+    pub fn read(&self, after: u64, until: u64) -> Vec<E>
+    where
+        E: Clone,
+    {
+        self.queue
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(tick, _)| *tick > after && *tick <= until)
+            .map(|(_, event)| event.clone())
+            .collect()
+    }
+}
+
+impl<E> Default for Events<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E: Send + 'static> EventQueue for Events<E> {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self as &dyn std::any::Any
+    }
+
+    fn clear_before(&mut self, tick: u64) {
+        self.queue.get_mut().unwrap().retain(|(sent, _)| *sent > tick);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_a_window_of_ticks() {
+        let mut events = Events::new();
+        events.send(1, "one");
+        events.send(2, "two");
+        events.send(3, "three");
+        assert_eq!(events.read(1, 2), ["two"]);
+        assert_eq!(events.read(0, 3), ["one", "two", "three"]);
+        events.clear_before(2);
+        assert_eq!(events.read(0, 3), ["three"]);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::spatial::Aabb;
+
+/// Convex shape in world space, ready for separating axis tests
+#[derive(Debug, Clone, PartialEq)]
+pub enum Convex {
+    /// Corners in order around the outside
+    Polygon(Vec<(f64, f64)>),
+    Circle { center: (f64, f64), radius: f64 },
+}
+
+impl Convex {
+    /// Rectangle covering a box
+    pub fn rectangle((x, y, width, height): Aabb) -> Self {
+        Convex::Polygon(vec![(x, y), (x + width, y), (x + width, y + height), (x, y + height)])
+    }
+
+    /// Smallest box around the shape
+    pub fn bounds(&self) -> Aabb {
+        match self {
+            Convex::Polygon(points) => {
+                let (mut min_x, mut min_y, mut max_x, mut max_y) = (f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY);
+                for &(x, y) in points {
+                    min_x = min_x.min(x);
+                    min_y = min_y.min(y);
+                    max_x = max_x.max(x);
+                    max_y = max_y.max(y);
+                }
+                (min_x, min_y, max_x - min_x, max_y - min_y)
+            },
+            Convex::Circle { center, radius } => (center.0 - radius, center.1 - radius, radius * 2.0, radius * 2.0),
+        }
+    }
+
+    // lowest and highest point of the shape along an axis
+    fn project(&self, axis: (f64, f64)) -> (f64, f64) {
+        match self {
+            Convex::Polygon(points) => points.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &point| {
+                let along = dot(point, axis);
+                (min.min(along), max.max(along))
+            }),
+            Convex::Circle { center, radius } => {
+                let along = dot(*center, axis);
+                (along - radius, along + radius)
+            },
+        }
+    }
+
+    // axes that could separate this shape from another one
+    fn axes(&self, other: &Convex) -> Vec<(f64, f64)> {
+        match self {
+            Convex::Polygon(points) => (0..points.len())
+                .filter_map(|index| {
+                    let (start, end) = (points[index], points[(index + 1) % points.len()]);
+                    normalize((end.1 - start.1, start.0 - end.0))
+                })
+                .collect(),
+            // circles are separated along the line to the nearest part of the other shape
+            Convex::Circle { center, .. } => {
+                let nearest = match other {
+                    Convex::Polygon(points) => points
+                        .iter()
+                        .copied()
+                        .min_by(|a, b| distance_squared(*a, *center).total_cmp(&distance_squared(*b, *center))),
+                    Convex::Circle { center: other_center, .. } => Some(*other_center),
+                };
+                nearest
+                    .and_then(|nearest| normalize((center.0 - nearest.0, center.1 - nearest.1)))
+                    .into_iter()
+                    .collect()
+            },
+        }
+    }
+}
+
+/// Separating axis test between two convex shapes. If they overlap, returns the unit normal
+/// to push `a` along to get it out of `b`, and how far it has to go.
+/// Shapes that only touch don't count as overlapping.
+pub fn separate(a: &Convex, b: &Convex) -> Option<((f64, f64), f64)> {
+    let mut axes = a.axes(b);
+    axes.extend(b.axes(a));
+
+    let mut best: Option<((f64, f64), f64)> = None;
+    for axis in axes {
+        let (min_a, max_a) = a.project(axis);
+        let (min_b, max_b) = b.project(axis);
+        let overlap = max_a.min(max_b) - min_a.max(min_b);
+        if overlap <= 0.0 {
+            return None;
+        }
+        // ties keep the earlier axis, so boxes settle on top of each other rather than beside
+        if best.is_none_or(|(_, depth)| overlap < depth) {
+            let normal = if min_a + max_a < min_b + max_b { (-axis.0, -axis.1) } else { axis };
+            best = Some((normal, overlap));
+        }
+    }
+    best
+}
+
+fn dot(a: (f64, f64), b: (f64, f64)) -> f64 {
+    a.0 * b.0 + a.1 * b.1
+}
+
+fn distance_squared(a: (f64, f64), b: (f64, f64)) -> f64 {
+    (a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)
+}
+
+fn normalize(vector: (f64, f64)) -> Option<(f64, f64)> {
+    let length = dot(vector, vector).sqrt();
+    if length == 0.0 {
+        None
+    } else {
+        Some((vector.0 / length, vector.1 / length))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 45° ramp rising to the right
+    fn ramp() -> Convex {
+        Convex::Polygon(vec![(0.0, 0.0), (16.0, 0.0), (16.0, 16.0)])
+    }
+
+    fn assert_near(actual: (f64, f64), expected: (f64, f64)) {
+        assert!((actual.0 - expected.0).abs() < 1e-9 && (actual.1 - expected.1).abs() < 1e-9, "{:?} isn't {:?}", actual, expected);
+    }
+
+    #[test]
+    fn boxes_are_pushed_out_of_slopes_along_the_slope_normal() {
+        // the bottom right corner is sunk into the ramp
+        let (normal, depth) = separate(&Convex::rectangle((4.0, 2.0, 8.0, 8.0)), &ramp()).unwrap();
+        let diagonal = std::f64::consts::FRAC_1_SQRT_2;
+        assert_near(normal, (-diagonal, diagonal));
+        assert!((depth - 10.0 * diagonal).abs() < 1e-9, "{}", depth);
+    }
+
+    #[test]
+    fn boxes_are_pushed_out_along_the_shallowest_axis() {
+        let (normal, depth) = separate(&Convex::rectangle((0.0, 12.0, 16.0, 16.0)), &Convex::rectangle((0.0, 0.0, 16.0, 16.0))).unwrap();
+        assert_near(normal, (0.0, 1.0));
+        assert_eq!(depth, 4.0);
+    }
+
+    #[test]
+    fn circles_are_pushed_out_of_slopes() {
+        // the center is 1.5 straight below the slope, which is 1.5 / √2 along the normal
+        let circle = Convex::Circle { center: (12.0, 10.5), radius: 2.0 };
+        let (normal, depth) = separate(&circle, &ramp()).unwrap();
+        let diagonal = std::f64::consts::FRAC_1_SQRT_2;
+        assert_near(normal, (-diagonal, diagonal));
+        assert!((depth - (2.0 + 1.5 * diagonal)).abs() < 1e-9, "{}", depth);
+    }
+
+    #[test]
+    fn shapes_apart_on_any_axis_dont_overlap() {
+        // inside the ramp's bounds, but above the slope
+        assert_eq!(separate(&Convex::rectangle((0.0, 10.0, 4.0, 4.0)), &ramp()), None);
+        assert_eq!(separate(&Convex::Circle { center: (4.0, 12.0), radius: 2.0 }, &ramp()), None);
+        // touching isn't overlapping
+        assert_eq!(separate(&Convex::rectangle((16.0, 0.0, 4.0, 4.0)), &ramp()), None);
+        assert_eq!(separate(&Convex::Circle { center: (0.0, 0.0), radius: 1.0 }, &Convex::Circle { center: (3.0, 0.0), radius: 1.0 }), None);
+    }
+}
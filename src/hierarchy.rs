@@ -0,0 +1,62 @@
+use crate::Coordinates;
+use serde::{Deserialize, Serialize};
+
+use crate::query::Without;
+use crate::{EntityId, World};
+
+/// Entity this one is attached to
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Parent(pub EntityId);
+
+/// Entities attached to this one, kept in sync by `World::set_parent`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Children(pub Vec<EntityId>);
+
+/// Position relative to the parent's `Coordinates`.
+///
+/// Entities with a `Parent` and a `LocalOffset` have their `Coordinates`
+/// overwritten by `propagate_transforms`, so move them by changing the offset.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct LocalOffset {
+    pub x: f64,
+    pub y: f64,
+}
+
+/// Writes world `Coordinates` for every attached entity, walking down from the
+/// entities that have children but no parent of their own
+pub fn propagate_transforms(world: &World) {
+    let mut roots = world.query_filtered::<(&Children,), Without<Parent>>();
+    let mut children = world.query::<(&Children,)>();
+    let mut offsets = world.query::<(&LocalOffset,)>();
+    let mut coordinates = world.query::<(&mut Coordinates,)>();
+
+    let mut stack: Vec<(EntityId, f64, f64)> = Vec::new();
+    for (root, (root_children,)) in roots.iter() {
+        let (x, y) = match coordinates.get(root) {
+            Some((root_coordinates,)) => (root_coordinates.coord_x, root_coordinates.coord_y),
+            None => continue,
+        };
+        stack.extend(root_children.0.iter().map(|&child| (child, x, y)));
+
+        while let Some((entity, parent_x, parent_y)) = stack.pop() {
+            let (mut x, mut y) = (parent_x, parent_y);
+            if let Some((offset,)) = offsets.get(entity) {
+                x += offset.x;
+                y += offset.y;
+                if let Some((mut entity_coordinates,)) = coordinates.get(entity) {
+                    entity_coordinates.coord_x = x;
+                    entity_coordinates.coord_y = y;
+                }
+            } else if let Some((entity_coordinates,)) = coordinates.get(entity) {
+                // Children without an offset keep their own position and pass it on
+                x = entity_coordinates.coord_x;
+                y = entity_coordinates.coord_y;
+            }
+
+            if let Some((grandchildren,)) = children.get(entity) {
+                stack.extend(grandchildren.0.iter().map(|&child| (child, x, y)));
+            }
+        }
+    }
+}
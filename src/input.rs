@@ -1,18 +1,99 @@
-use gametesting::Collider;
+use crate::Collider;
 use winit_input_helper::WinitInputHelper;
 use std::collections::HashMap;
 use std::clone::Clone;
-use gilrs::{Gamepad, Button};
+use gilrs::Button;
 
-use gametesting::Collision;
-use gametesting::Sprite;
+use crate::Side;
+use crate::Sprite;
 
+use crate::query::With;
+use crate::tags::Player;
 use crate::World;
 
+/// Raw input, filled in by the event loop before the schedule runs
+pub struct FrameInput {
+    pub keyboard: WinitInputHelper,
+    pub gamepad_held: Vec<Button>,
+    pub gamepad_events: (Vec<Button>, Vec<Button>),
+    pressed: Vec<GameInput>,
+    released: Vec<GameInput>,
+}
+
+impl FrameInput {
+    pub fn new() -> Self {
+        Self {
+            keyboard: WinitInputHelper::new(),
+            gamepad_held: Vec::new(),
+            gamepad_events: (Vec::new(), Vec::new()),
+            pressed: Vec::new(),
+            released: Vec::new(),
+        }
+    }
+
+    /// Remembers which bound actions were pressed or released on this pass of the event loop.
+    ///
+    /// Presses and releases only last one pass, but a fixed step doesn't run on every pass,
+    /// so they're kept until the next step handles them, and only that step sees them.
+    pub fn collect_actions(&mut self, input_map: &InputMap) {
+        let handler = InputHandler {
+            input_map: &input_map.bindings,
+            keyboard: &self.keyboard,
+            gamepad_held: &self.gamepad_held,
+            gamepad_events: &self.gamepad_events,
+        };
+        let mut pressed = Vec::new();
+        let mut released = Vec::new();
+        for action in input_map.bindings.keys() {
+            if handler.check(action, InputState::Pressed) {
+                pressed.push(*action);
+            }
+            if handler.check(action, InputState::Released) {
+                released.push(*action);
+            }
+        }
+        self.pressed.extend(pressed);
+        self.released.extend(released);
+    }
+}
+
+impl Default for FrameInput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Maps game actions to the keys or buttons that trigger them
+pub struct InputMap {
+    bindings: HashMap<GameInput, UserInput>,
+}
+
+impl InputMap {
+    pub fn new() -> Self {
+        Self { bindings: HashMap::new() }
+    }
+
+    pub fn bind(&mut self, game_input: GameInput, user_input: UserInput) {
+        self.bindings.insert(game_input, user_input);
+    }
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Cursor position in game pixels, from the bottom left of the screen like the camera
+pub struct MousePosition {
+    pub x: i32,
+    pub y: i32,
+}
+
 struct InputHandler<'a> {
     input_map: &'a HashMap<GameInput, UserInput>,
     keyboard: &'a WinitInputHelper,
-    gamepad: Option<&'a Gamepad<'a>>,
+    gamepad_held: &'a Vec<Button>,
     gamepad_events: &'a (Vec<Button>, Vec<Button>),
 }
 
@@ -23,112 +104,182 @@ impl<'a> InputHandler<'a> {
         match mapped_input {
             None => {
                 println!("You've done messed up the input process.");
-                return false;
+                false
             },
             Some(userinput) => {
                 match userinput {
                     UserInput::ControllerInput(button) => {
-                        if let Some(active_pad) = self.gamepad {
-                            match input_state {
-                                InputState::Held => return active_pad.is_pressed(button.clone()),
-                                InputState::Pressed => return self.gamepad_events.0.contains(button),
-                                InputState::Released => return self.gamepad_events.1.contains(button),
-                            }
+                        match input_state {
+                            InputState::Held => self.gamepad_held.contains(button),
+                            InputState::Pressed => self.gamepad_events.0.contains(button),
+                            InputState::Released => self.gamepad_events.1.contains(button),
                         }
                     },
                     UserInput::KeyboardInput(keycode) => {
                         match input_state {
-                            InputState::Held => return self.keyboard.key_held(keycode.clone()),
-                            InputState::Pressed => return self.keyboard.key_pressed(keycode.clone()),
-                            InputState::Released => return self.keyboard.key_released(keycode.clone()),
+                            InputState::Held => self.keyboard.key_held(*keycode),
+                            InputState::Pressed => self.keyboard.key_pressed(*keycode),
+                            InputState::Released => self.keyboard.key_released(*keycode),
+                        }
+                    },
+                    UserInput::MouseInput(button) => {
+                        match input_state {
+                            InputState::Held => self.keyboard.mouse_held(*button),
+                            InputState::Pressed => self.keyboard.mouse_pressed(*button),
+                            InputState::Released => self.keyboard.mouse_released(*button),
                         }
                     },
                 }
             },
         }
-        true
     }
 }
 
-#[derive(Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub enum GameInput {
     PlayerLeft,
     PlayerRight,
     PlayerUp,
     PlayerDown,
     PlayerAccept,
+    PlayerGrapple,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum InputState {
     Pressed,
     Held,
     Released
 }
 
+/// Sent when a bound action is pressed or released
+#[derive(Debug, Clone)]
+pub struct ActionEvent {
+    pub action: GameInput,
+    pub state: InputState,
+}
+
 #[derive(Clone)]
 pub enum UserInput {
     ControllerInput(gilrs::ev::Button),
     KeyboardInput(winit::event::VirtualKeyCode),
+    /// Mouse button, 0 is left, 1 is right and 2 is middle
+    MouseInput(usize),
 }
 
-pub fn handle_input(world: &mut World, input: &WinitInputHelper, gamepad: Option<&Gamepad>, gamepad_events: &(Vec<Button>, Vec<Button>)) {
-    let input_map = &world.input_map;
+/// Moves the player and sends an `ActionEvent` for every action collected since the last step
+pub fn handle_input(world: &World) {
+    let input_map = world.resource::<InputMap>().unwrap();
+    let mut frame_input = world.resource_mut::<FrameInput>().unwrap();
+    let pressed = std::mem::take(&mut frame_input.pressed);
+    let released = std::mem::take(&mut frame_input.released);
     let handler = InputHandler {
-        input_map: input_map,
-        keyboard: input,
-        gamepad: gamepad,
-        gamepad_events,
+        input_map: &input_map.bindings,
+        keyboard: &frame_input.keyboard,
+        gamepad_held: &frame_input.gamepad_held,
+        gamepad_events: &frame_input.gamepad_events,
     };
     
-    let mut entities = world.borrow_component_vec_mut::<Collider>().unwrap();
-    let mut sprites = world.borrow_component_vec_mut::<Sprite>().unwrap();
-    let player = &mut entities[0];
-    let player_sprite = &mut sprites[0];
+    for &action in pressed.iter() {
+        world.send(ActionEvent { action, state: InputState::Pressed });
+    }
+    for &action in released.iter() {
+        world.send(ActionEvent { action, state: InputState::Released });
+    }
+    
+    let mut players = world.query_filtered::<(&mut Collider, &mut Sprite), With<Player>>();
     
-    if let (Some(player_collider), Some(sprite)) = (player, player_sprite) {
+    for (_, (mut player_collider, mut sprite)) in players.iter() {
         if handler.check(&GameInput::PlayerLeft, InputState::Held) {
-            player_collider.vel_x = -0.01;
+            player_collider.vel_x = -60.0;
             sprite.reversed = true;
         }
         
-        if handler.check(&GameInput::PlayerLeft, InputState::Released) {
-            player_collider.vel_x = 0.00;
+        if released.contains(&GameInput::PlayerLeft) {
+            player_collider.vel_x = 0.0;
         }
         
         if handler.check(&GameInput::PlayerRight, InputState::Held) {
-            player_collider.vel_x = 0.01;
+            player_collider.vel_x = 60.0;
             sprite.reversed = false;
         }
         
-        if handler.check(&GameInput::PlayerRight, InputState::Released) {
-            player_collider.vel_x = 0.00;
+        if released.contains(&GameInput::PlayerRight) {
+            player_collider.vel_x = 0.0;
         }
-        println!("{:?}", player_collider.grounded);
-        //println!("YVEL: {}", player_collider.vel_y);
-        //println!("XVEL: {}", player_collider.vel_x);
-        if handler.check(&GameInput::PlayerUp, InputState::Pressed) && player_collider.grounded.is_some() {
-            //player_collider.vel_y = 0.1;
+        
+        if pressed.contains(&GameInput::PlayerUp) && player_collider.grounded.is_some() {
+            //player_collider.vel_y = 600.0;
             //player_collider.grounded = None;
             
-            match player_collider.grounded {
-                Some(Collision::Left) => {
-                    player_collider.vel_y += 0.07;
-                    player_collider.vel_x += 0.5;
+            match player_collider.grounded.map(|grounded| grounded.side) {
+                Some(Side::Left) => {
+                    player_collider.vel_y += 420.0;
+                    player_collider.vel_x += 3000.0;
                 },
-                Some(Collision::Right) => {
-                    player_collider.vel_y += 0.07;
-                    player_collider.vel_x -= 0.5;
+                Some(Side::Right) => {
+                    player_collider.vel_y += 420.0;
+                    player_collider.vel_x -= 3000.0;
                 },
-                Some(Collision::Down) => {
-                    player_collider.vel_y = 0.1;
+                Some(Side::Down) => {
+                    player_collider.vel_y = 600.0;
                 },
-                _ => {println!("HUH")},
+                _ => {},
             }
         }
         
+        if pressed.contains(&GameInput::PlayerDown) {
+            player_collider.drop_through = true;
+        }
+        
         if handler.check(&GameInput::PlayerDown, InputState::Held) {
-            player_collider.vel_y = -0.1;
+            player_collider.vel_y = -600.0;
         }
         
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::{Schedule, Stage};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn presses_reach_exactly_one_step() {
+        let mut world = World::new();
+        let mut input_map = InputMap::new();
+        input_map.bind(GameInput::PlayerAccept, UserInput::ControllerInput(Button::South));
+        world.insert_resource(input_map);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut schedule = Schedule::new();
+        schedule.add_system(Stage::Input, "handle_input", handle_input)
+            .reads::<InputMap>()
+            .writes::<FrameInput>()
+            .reads::<Player>()
+            .writes::<Collider>()
+            .writes::<Sprite>();
+        let reader = seen.clone();
+        schedule.add_system(Stage::Render, "reader", move |world: &World| {
+            reader.lock().unwrap().extend(world.read::<ActionEvent>().into_iter().map(|event| event.state));
+        });
+
+        // a press on a pass that runs no steps is still seen by the next step
+        let pass = |world: &World, events: (Vec<Button>, Vec<Button>)| {
+            let map = world.resource::<InputMap>().unwrap();
+            let mut frame_input = world.resource_mut::<FrameInput>().unwrap();
+            frame_input.gamepad_events = events;
+            frame_input.collect_actions(&map);
+        };
+        pass(&world, (vec![Button::South], Vec::new()));
+        pass(&world, (Vec::new(), Vec::new()));
+        // and several steps on one pass only handle it once
+        schedule.run(&mut world);
+        schedule.run(&mut world);
+        assert_eq!(*seen.lock().unwrap(), [InputState::Pressed]);
+
+        pass(&world, (Vec::new(), vec![Button::South]));
+        schedule.run(&mut world);
+        assert_eq!(*seen.lock().unwrap(), [InputState::Pressed, InputState::Released]);
+    }
+}
@@ -1,18 +1,36 @@
+pub mod commands;
+pub mod events;
+pub mod geometry;
+pub mod hierarchy;
+pub mod input;
+pub mod physics;
+pub mod player;
+pub mod prefab;
+pub mod query;
+pub mod render;
+pub mod schedule;
+pub mod snapshot;
+pub mod spatial;
+pub mod tags;
+pub mod world;
+pub mod worldinit;
+
+pub use world::{default_schedule, EntityId, Time, World};
+
 use std::fs::File;
-use std::collections::HashMap;
-const GAME_HEIGHT: i32 = 240;
-const GAME_WIDTH: i32 = 426;
-use std::cell::RefCell;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::io::Read;
 use serde::{Serialize, Deserialize};
 
-pub trait ComponentVec {
+pub trait ComponentVec: Send + Sync {
     fn as_any(&self) -> &dyn std::any::Any;
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any;
-    fn push_none(&mut self);
+    /// Returns true if the entity had this component
+    fn remove(&mut self, entity: usize) -> bool;
 }
 
-impl<T: 'static> ComponentVec for RefCell<Vec<Option<T>>> {
+impl<T: Send + Sync + 'static> ComponentVec for RwLock<SparseSet<T>> {
     fn as_any(&self) -> &dyn std::any::Any {
         self as &dyn std::any::Any
     }
@@ -21,70 +39,342 @@ impl<T: 'static> ComponentVec for RefCell<Vec<Option<T>>> {
         self as &mut dyn std::any::Any
     }
 
-    fn push_none(&mut self) {
-        self.get_mut().push(None)
+    fn remove(&mut self, entity: usize) -> bool {
+        self.get_mut().unwrap().remove(entity).is_some()
+    }
+}
+
+// Shared by every storage so a version is never handed out twice,
+// not even to storages made when a snapshot is loaded
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+/// Component storage that keeps every component packed together
+///
+/// `sparse` maps an entity index to its position in `dense`, and `entities` maps
+/// back the other way, so iterating only touches entities that have the component.
+/// `added` and `changed` hold the world tick each component was inserted and last changed at.
+pub struct SparseSet<T> {
+    sparse: Vec<Option<usize>>,
+    dense: Vec<T>,
+    entities: Vec<usize>,
+    added: Vec<u64>,
+    changed: Vec<u64>,
+    version: u64,
+}
+
+impl<T> SparseSet<T> {
+    pub fn new() -> Self {
+        Self {
+            sparse: Vec::new(),
+            dense: Vec::new(),
+            entities: Vec::new(),
+            added: Vec::new(),
+            changed: Vec::new(),
+            version: 0,
+        }
+    }
+
+    /// Changes whenever a component may have been added, removed or written,
+    /// so anything worked out from the storage can tell when it's out of date
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Gives the storage a new version, for when it's about to be written to
+    pub fn mark_modified(&mut self) {
+        self.version = NEXT_VERSION.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Inserts or replaces the component for an entity
+    pub fn insert(&mut self, entity: usize, value: T, tick: u64) {
+        self.mark_modified();
+        if let Some(position) = self.position(entity) {
+            self.dense[position] = value;
+            self.changed[position] = tick;
+            return;
+        }
+        if entity >= self.sparse.len() {
+            self.sparse.resize(entity + 1, None);
+        }
+        self.sparse[entity] = Some(self.dense.len());
+        self.dense.push(value);
+        self.entities.push(entity);
+        self.added.push(tick);
+        self.changed.push(tick);
+    }
+
+    /// Removes the component for an entity, moving the last component into its place
+    pub fn remove(&mut self, entity: usize) -> Option<T> {
+        let position = self.position(entity)?;
+        self.mark_modified();
+        self.sparse[entity] = None;
+        self.entities.swap_remove(position);
+        self.added.swap_remove(position);
+        self.changed.swap_remove(position);
+        if let Some(&moved) = self.entities.get(position) {
+            self.sparse[moved] = Some(position);
+        }
+        Some(self.dense.swap_remove(position))
+    }
+
+    /// Index into the packed storage for an entity, if it has this component
+    pub fn position(&self, entity: usize) -> Option<usize> {
+        *self.sparse.get(entity)?
+    }
+
+    pub fn contains(&self, entity: usize) -> bool {
+        self.position(entity).is_some()
+    }
+
+    pub fn get(&self, entity: usize) -> Option<&T> {
+        Some(&self.dense[self.position(entity)?])
+    }
+
+    pub fn get_mut(&mut self, entity: usize) -> Option<&mut T> {
+        let position = self.position(entity)?;
+        Some(&mut self.dense[position])
+    }
+
+    /// Ticks the entity's component was added and last changed at
+    pub fn ticks(&self, entity: usize) -> Option<(u64, u64)> {
+        let position = self.position(entity)?;
+        Some((self.added[position], self.changed[position]))
+    }
+
+    pub fn len(&self) -> usize {
+        self.dense.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dense.is_empty()
+    }
+
+    /// Entity indices in the same order as the packed components
+    pub fn entities(&self) -> &[usize] {
+        &self.entities
+    }
+
+    /// Raw pointer to the packed components, used by queries to hand out
+    /// several `&mut T` to different entities at once
+    pub fn dense_ptr(&mut self) -> *mut T {
+        self.dense.as_mut_ptr()
+    }
+
+    /// Raw pointer to the changed ticks, so `&mut T` query items can stamp them
+    pub fn changed_ptr(&mut self) -> *mut u64 {
+        self.changed.as_mut_ptr()
+    }
+
+    /// Raw pointer to the added ticks, read alongside `changed_ptr` without
+    /// going through the storage
+    pub fn added_ptr(&self) -> *const u64 {
+        self.added.as_ptr()
+    }
+}
+
+impl<T> Default for SparseSet<T> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 /// Rectangular collider with optional collision
 ///
-/// Boundary box defined as x1, y1, x2, y2
+/// Boundary box defined as x1, y1, x2, y2 relative to the entity's `Coordinates`,
+/// with y pointing up like the rest of the world. `(0.0, 0.0, 16.0, 16.0)` covers a
+/// 16x16 sprite exactly, and `(4.0, 0.0, 12.0, 14.0)` is a narrower hitbox standing
+/// on the same spot.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct Collider {
+    /// Rigid bodies touching this collider cling to it instead of falling
     pub sticky: bool,
+    /// Only stops bodies landing on it from above, so they can jump up through it
+    pub one_way: bool,
+    /// Falls through one-way colliders for the next physics step
+    pub drop_through: bool,
     pub rigid_body: bool,
     pub active: bool,
+    /// Solid when true. Otherwise this is a trigger volume that sends `TriggerEvent`s
+    /// for whatever overlaps it and lets it pass.
     pub collision: bool,
     pub boundary: (f64, f64, f64, f64),
+    /// Shape of the collider within its boundary
+    pub outline: Outline,
     pub vel_x: f64,
     pub vel_y: f64,
-    pub grounded: Option<Collision>
+    pub grounded: Option<Collision>,
+    /// Side this body is stuck to a sticky collider on. Gravity is suspended until it
+    /// moves away from the surface or the surface is gone.
+    pub clinging: Option<Side>,
+    /// Sweep moving bodies along their velocity instead of just checking where they end up,
+    /// so they can't skip through thin walls when moving fast. Kinematic bodies such as
+    /// projectiles stop where they hit, rigid bodies slide along what they hit.
+    /// Only rectangles are swept, other outlines are checked where they end up.
+    pub continuous: bool,
+    /// Extra shapes moving with the boundary
+    pub shapes: Vec<Shape>,
+    /// Bits of the layers this collider is on, named in `physics::CollisionLayers`
+    pub layers: u32,
+    /// Bits of the layers this collider collides with. Two colliders only interact
+    /// when each is on a layer in the other's mask.
+    pub mask: u32,
 }
 
-#[derive(Debug)]
-pub enum Collision {
+/// Extra shape on a collider, in the same coordinates as `Collider::boundary`.
+/// Sensors report collisions without pushing anything, like a box under the feet to check for ground.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Shape {
+    pub boundary: (f64, f64, f64, f64),
+    pub outline: Outline,
+    pub sensor: bool,
+}
+
+/// Shape of a collider within its boundary box
+///
+/// Slopes are polygons, such as a 45° ramp rising to the right on a 16x16 tile:
+/// ```ron
+/// outline: Polygon([(0.0, 0.0), (16.0, 0.0), (16.0, 16.0)]),
+/// ```
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub enum Outline {
+    /// Fills the boundary
+    #[default]
+    Rectangle,
+    /// Largest circle that fits in the middle of the boundary
+    Circle,
+    /// Convex polygon with corners relative to the entity's `Coordinates`, like the boundary.
+    /// The boundary is worked out from the corners, so it can be left out.
+    Polygon(Vec<(f64, f64)>),
+}
+
+impl Default for Collider {
+    /// A solid box that doesn't move
+    fn default() -> Self {
+        Self {
+            sticky: false,
+            one_way: false,
+            drop_through: false,
+            rigid_body: false,
+            active: false,
+            collision: true,
+            boundary: (0.0, 0.0, 0.0, 0.0),
+            outline: Outline::Rectangle,
+            vel_x: 0.0,
+            vel_y: 0.0,
+            grounded: None,
+            clinging: None,
+            continuous: false,
+            shapes: Vec::new(),
+            layers: physics::DEFAULT_LAYER,
+            mask: u32::MAX,
+        }
+    }
+}
+
+/// Side of a body that something touches
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Side {
     Left,
     Right,
     Down,
     Up,
 }
 
+impl Side {
+    pub fn opposite(self) -> Self {
+        match self {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+            Side::Down => Side::Up,
+            Side::Up => Side::Down,
+        }
+    }
+}
+
+/// Contact between a body and something it overlapped
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Collision {
+    /// Side of the body the contact is on, going by where the normal mostly points
+    pub side: Side,
+    /// Unit vector the body gets pushed along to stop overlapping
+    pub normal: (f64, f64),
+    /// How far the body overlapped along the normal
+    pub depth: f64,
+}
+
+impl Collision {
+    /// Contact pushing along `normal`. Normals at exactly 45° count as standing on
+    /// or hitting the ceiling, so 45° slopes can be walked on.
+    pub fn new(normal: (f64, f64), depth: f64) -> Self {
+        let side = if normal.1.abs() >= normal.0.abs() {
+            if normal.1 > 0.0 { Side::Down } else { Side::Up }
+        } else if normal.0 > 0.0 {
+            Side::Left
+        } else {
+            Side::Right
+        };
+        Self { side, normal, depth }
+    }
+
+    /// Contact flat against one side of the body
+    pub fn on_side(side: Side, depth: f64) -> Self {
+        let normal = match side {
+            Side::Left => (1.0, 0.0),
+            Side::Right => (-1.0, 0.0),
+            Side::Down => (0.0, 1.0),
+            Side::Up => (0.0, -1.0),
+        };
+        Self { side, normal, depth }
+    }
+
+    /// The same contact as seen by the other body
+    pub fn flipped(self) -> Self {
+        Self {
+            side: self.side.opposite(),
+            normal: (-self.normal.0, -self.normal.1),
+            depth: self.depth,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct Sprite {
     pub visible: bool,
-    pub sprite: &'static str,
+    pub sprite: String,
     pub sprite_state: (u32, u32),
     pub time_left: f64,
     pub fade: bool,
     pub reversed: bool,
 }
 
+impl Default for Sprite {
+    fn default() -> Self {
+        Self {
+            visible: true,
+            sprite: String::new(),
+            sprite_state: (0, 0),
+            time_left: 0.0,
+            fade: false,
+            reversed: false,
+        }
+    }
+}
+
 pub struct Text {
     pub text: &'static str,
     pub speed: f64,
 }
 
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
 pub struct Coordinates {
     pub coord_x: f64,
     pub coord_y: f64,
 }
 
-pub trait Object {
-    /// Returns pixels relative to the current camera
-    fn get_pixels(&self, camera: &Camera, mouse_pos: &(i32, i32)) -> Vec<Pixel>;
-    fn update(&mut self, camera: &Camera, renderable_entities: &mut HashMap<i32,i32>);
-}
-
-pub struct Entity {
-    pub id: i32,
-    pub visible: bool,
-    pub collision: bool,
-    pub hitbox_x: f64,
-    pub hitbox_y: f64,
-    pub coord_x: f64,
-    pub coord_y: f64,
-    pub sprite: String,
-    pub sprite_state: u32,
-}
-
 pub struct Image {
     pub name: String,
     pub bytes: Vec<u8>,
@@ -151,78 +441,16 @@ fn flip_pixels_x_axis(pixels: &mut Vec<u8>, width: usize, height: usize) {
     }
 }
 
-impl Object for Entity {
-    fn get_pixels(&self, camera: &Camera, mouse_pos: &(i32, i32)) -> Vec<Pixel> {
-        let mut block = Vec::new();
-        let decoder = png::Decoder::new(File::open(&self.sprite).unwrap());
-        let mut reader = decoder.read_info().unwrap();
-        // Allocate the output buffer.
-        let mut buf = vec![0; reader.output_buffer_size()];
-        // Read the next frame. An APNG might contain multiple frames.
-        let info = reader.next_frame(&mut buf).unwrap();
-        // Grab the bytes of the image.
-        let bytes = &buf[..info.buffer_size()];
-
-        //draws sprite
-        let mut pos: usize = 0;
-        for y in 0..info.height {
-            for x in 0..info.width {
-                //if self.coord_x as i32 + x as i32-4 < 426 && self.coord_y as i32 + y as i32- 4 > 0 {
-                block.push(Pixel {
-                    x: self.coord_x as i32 - camera.x + x as i32,
-                    y: -(self.coord_y as i32 - camera.y + y as i32) + info.height as i32 - 1,
-                    rgba: [
-                        bytes[pos * 4 + 0],
-                        bytes[pos * 4 + 1],
-                        bytes[pos * 4 + 2],
-                        bytes[pos * 4 + 3],
-                    ],
-                });
-                pos += 1;
-                //}
-            }
-        }
-        return block;
-    }
-    //TODO: make this actually function
-    fn update(&mut self, camera: &Camera, renderable_entities: &mut HashMap<i32,i32>) {
-        if (self.coord_x as i32) < camera.x || (self.coord_x as i32) > camera.x + GAME_WIDTH {
-            self.visible = true;
-            renderable_entities.insert(self.id, self.id);
-        } else {
-            self.visible = true;
-            renderable_entities.insert(self.id, self.id);
-        }
-    }
-}
-
-#[derive(Debug, Copy, Clone)]
-pub struct Pixel {
-    pub x: i32,
-    pub y: i32,
-    pub rgba: [u8; 4],
-}
-
-/// Player controlled object
-pub struct Player {
-    pub health: i8,
-    pub coord_x: f64,
-    pub coord_y: f64,
-    pub velocity_x: f64,
-    pub velocity_y: f64,
-    pub grounded: bool,
-    pub grappled: bool,
-    pub grapple_loc: (i32, i32),
-}
-
 /// World camera, coordinates are the bottom left of camera
+#[derive(Clone)]
 pub struct Camera {
     pub x: i32,
     pub y: i32,
 }
 
 impl Camera {
-    pub fn update(&mut self, player: &Entity) {
+    /// Keeps the followed position in view, never scrolling past the bottom left of the world
+    pub fn update(&mut self, player: &Coordinates) {
         if (player.coord_y as i32) > 90 {
             self.y = player.coord_y as i32 - 90;
         } else {
@@ -237,159 +465,60 @@ impl Camera {
     }
 }
 
-impl Object for Player {
-    fn get_pixels(&self, camera: &Camera, mouse_pos: &(i32, i32)) -> Vec<Pixel> {
-        let mut block = Vec::new();
-        let decoder = png::Decoder::new(File::open("sprites/steve.png").unwrap());
-        let mut reader = decoder.read_info().unwrap();
-        // Allocate the output buffer.
-        let mut buf = vec![0; reader.output_buffer_size()];
-        // Read the next frame. An APNG might contain multiple frames.
-        let info = reader.next_frame(&mut buf).unwrap();
-        // Grab the bytes of the image.
-        let bytes = &buf[..info.buffer_size()];
-
-        //draws player as block
-        let mut pos: usize = 0;
-        for y in 0..9 {
-            for x in 0..9 {
-                //if self.coord_x as i32 + x -4 < 320 && self.coord_y as i32 + y - 4 > 0 {
-                block.push(Pixel {
-                    x: self.coord_x as i32 - camera.x + x - 4,
-                    y: self.coord_y as i32 - camera.y + y - 4,
-                    rgba: [
-                        bytes[pos * 4 + 0],
-                        bytes[pos * 4 + 1],
-                        bytes[pos * 4 + 2],
-                        255,
-                    ],
-                });
-                pos += 1;
-                //}
-            }
-        }
-
-        //draws cursor position in green
-        block.push(Pixel {
-            x: mouse_pos.0 % GAME_WIDTH,
-            y: mouse_pos.1 - 1,
-            rgba: [0, 255, 0, 255],
-        });
-
-        //draws grapple spot
-        if self.grappled {
-            let x_relative = self.grapple_loc.0 - camera.x;
-            let y_relative = self.grapple_loc.1 - camera.y;
-
-            //checks if coordinates are in frame
-            if x_relative > 0
-                && x_relative < GAME_WIDTH
-                && y_relative < GAME_HEIGHT
-                && y_relative > 0
-            {
-                block.push(Pixel {
-                    x: x_relative,
-                    y: y_relative,
-                    rgba: [255, 0, 0, 255],
-                });
-            }
-        }
-
-        //draws grapple hook and rope when fired
-        let mut y_diff = (self.coord_y - mouse_pos.1 as f64 - camera.y as f64) as f64;
-        let mut x_diff = (self.coord_x - mouse_pos.0 as f64 - camera.x as f64) as f64;
-
-        let mut slope = 1.0;
-
-        if self.grappled {
-            y_diff = (self.coord_y - self.grapple_loc.1 as f64) as f64;
-            x_diff = (self.coord_x - self.grapple_loc.0 as f64) as f64;
-            if x_diff != 0.0 {
-                slope = y_diff / x_diff;
-            }
-        }
-
-        //angle of the grapple hook relative to the player
-        let mut mouse_angle = (y_diff / x_diff).atan();
-
-        //increase the mouse angle by 1 rad to make a full circle
-        if x_diff >= 0.0 {
-            mouse_angle += 3.141;
-        }
-
-        let grapple_hook_x = (mouse_angle.cos() * 15.0) as i32 + self.coord_x as i32 - camera.x;
-        let grapple_hook_y = (mouse_angle.sin() * 15.0) as i32 + self.coord_y as i32 - camera.y;
-
-        //draws grapple hook
-        if grapple_hook_x > 0 && grapple_hook_x < 320 && grapple_hook_y < 180 && grapple_hook_y > 0
-        {
-            block.push(Pixel {
-                x: grapple_hook_x as i32,
-                y: grapple_hook_y as i32,
-                rgba: [0, 0, 255, 255],
-            });
-        }
-
-        //draws grapple hook rope
-        if self.grappled {
-            let grapple_x_diff = self.grapple_loc.0 - grapple_hook_x - camera.x;
-            for mut x in 0..grapple_x_diff.abs() {
-                //goes in opposite direction if grapple spot is left of player
-                if grapple_x_diff < 0 {
-                    x *= -1;
-                }
-                let rope_x = grapple_hook_x as i32 + x;
-                let rope_y = grapple_hook_y as i32 + (x as f64 * slope) as i32;
-
-                if rope_x > 0 && rope_x < GAME_WIDTH && rope_y < GAME_HEIGHT && rope_y > 0 {
-                    block.push(Pixel {
-                        x: rope_x,
-                        y: rope_y,
-                        rgba: [0, 0, 255, 255],
-                    });
-                }
-            }
-        }
-
-        return block;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparse_set_keeps_components_packed() {
+        let mut set = SparseSet::new();
+        assert!(set.is_empty());
+        set.insert(3, "three", 1);
+        set.insert(0, "zero", 2);
+        set.insert(7, "seven", 3);
+        assert_eq!(set.len(), 3);
+        assert_eq!(set.get(0), Some(&"zero"));
+        assert_eq!(set.get(5), None);
+        assert_eq!(set.get(100), None);
+
+        // the last component moves into the removed one's place
+        assert_eq!(set.remove(3), Some("three"));
+        assert_eq!(set.remove(3), None);
+        assert_eq!(set.entities(), &[7, 0]);
+        assert_eq!(set.get(7), Some(&"seven"));
+        assert!(!set.contains(3));
     }
 
-    fn update(&mut self, camera: &Camera, renderable_entities: &mut HashMap<i32,i32>) {
-        //gravity and ground
-        self.coord_x += self.velocity_x;
-        self.coord_y += self.velocity_y;
-
-        //println!("X: {} Y: {}", self.coord_x, self.coord_y);
-        //println!("XVEL: {} YVEL: {}", self.velocity_x, self.velocity_y);
-
-        //TODO: clean up this mess
-        if self.coord_x < 4.0 {
-            self.coord_x = 4.0;
-        }
-
-        if self.coord_y <= 4.0 {
-            self.coord_y = 4.0;
-            self.velocity_y = 0.0;
-            self.grounded = true;
-            //println!("grounded")
-        } else {
-            self.grounded = false;
-            //println!("not grounded")
-        }
-
-        if self.grappled {
-            let y_diff = self.coord_y - self.grapple_loc.1 as f64;
-            let x_diff = self.coord_x - self.grapple_loc.0 as f64;
-            self.velocity_x -= x_diff * 0.0005;
-            self.velocity_y -= y_diff * 0.0007;
-        }
-
-        if self.coord_y > 4.1 {
-            self.velocity_y -= 0.025;
-        }
+    #[test]
+    fn sparse_set_tracks_ticks() {
+        let mut set = SparseSet::new();
+        set.insert(2, 1.0, 5);
+        assert_eq!(set.ticks(2), Some((5, 5)));
+        // replacing keeps the added tick
+        set.insert(2, 2.0, 9);
+        assert_eq!(set.ticks(2), Some((5, 9)));
+        assert_eq!(set.get(2), Some(&2.0));
+        assert_eq!(set.len(), 1);
+    }
 
-        if self.grounded {
-            self.velocity_x *= 0.99;
-        }
+    #[test]
+    fn sparse_set_versions_change_on_every_write() {
+        let mut set = SparseSet::new();
+        let mut versions = vec![set.version()];
+        set.insert(0, 'a', 1);
+        versions.push(set.version());
+        set.remove(1);
+        assert_eq!(set.version(), versions[1]);
+        set.remove(0);
+        versions.push(set.version());
+        set.mark_modified();
+        versions.push(set.version());
+        versions.sort();
+        versions.dedup();
+        assert_eq!(versions.len(), 4);
+        // fresh storages never reuse a version another storage had
+        let mut other = SparseSet::new();
+        other.insert(0, 'b', 1);
+        assert!(!versions.contains(&other.version()));
     }
 }
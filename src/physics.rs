@@ -1,150 +1,906 @@
-use gametesting::Collision;
-use gametesting::Coordinates;
-use gametesting::Collider;
-use crate::Camera;
-use std::borrow::BorrowMut;
-use std::time::Instant;
-use std::cell::RefMut;
-use std::collections::HashMap;
-
-/// Simulates one frame of physics
+use crate::{Collision, Side};
+use crate::Coordinates;
+use crate::{Collider, Outline};
+use crate::EntityId;
+use crate::geometry::{self, Convex};
+use crate::query::{Mut, Query};
+use crate::spatial::{overlaps, Aabb, SpatialHash};
+use std::collections::{HashMap, HashSet};
+
+// A body's index in this step's entities and one of its shape numbers
+type ShapeRef = (usize, usize);
+
+/// Sent whenever one of `entity`'s shapes touches one of `other`'s, with the contact as `entity` sees it.
+/// Shape 0 is the collider's `boundary` and shape `n` is `shapes[n - 1]`.
+#[derive(Debug, Clone)]
+pub struct CollisionEvent {
+    pub entity: EntityId,
+    pub other: EntityId,
+    pub collision: Collision,
+    pub shape: usize,
+    pub other_shape: usize,
+}
+
+/// Where an entity is relative to a trigger volume
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerState {
+    /// Started overlapping this step
+    Enter,
+    /// Was already overlapping on the last step
+    Stay,
+    /// Stopped overlapping, or one of the two is gone
+    Exit,
+}
+
+/// Sent every step that `other` overlaps `trigger`, which is any collider with `collision`
+/// turned off, and once more when it leaves
+#[derive(Debug, Clone)]
+pub struct TriggerEvent {
+    pub trigger: EntityId,
+    pub other: EntityId,
+    pub state: TriggerState,
+}
+
+/// Trigger and entity pairs that overlapped on the last step
+#[derive(Default)]
+pub struct TriggerContacts(pub HashSet<(EntityId, EntityId)>);
+
+/// Bit of the layer every collider starts on
+pub const DEFAULT_LAYER: u32 = 1;
+
+/// Named collision layers, each one a bit in `Collider::layers` and `Collider::mask`.
+/// Layers are registered at startup, with "default" always taking the first bit.
+///
+/// Enemy projectiles that hit the player and the terrain but not other enemies:
+/// ```ignore
+/// collider.layers = layers.bits(&["enemy_projectile"]);
+/// collider.mask = layers.bits(&["player", "terrain"]);
+/// ```
+pub struct CollisionLayers {
+    names: Vec<String>,
+}
+
+impl CollisionLayers {
+    pub fn new() -> Self {
+        Self { names: vec![String::from("default")] }
+    }
+
+    /// Adds a layer and returns its bit, or the bit it already has if it was registered before.
+    /// Panics if all 32 layers are taken.
+    pub fn register(&mut self, name: &str) -> u32 {
+        if let Some(bit) = self.get(name) {
+            return bit;
+        }
+        if self.names.len() == u32::BITS as usize {
+            panic!("no collision layers left for {}", name);
+        }
+        self.names.push(name.to_string());
+        1 << (self.names.len() - 1)
+    }
+
+    /// Bit of a registered layer
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.names.iter().position(|layer| layer == name).map(|index| 1 << index)
+    }
+
+    /// Bits of every named layer together. Names that were never registered are logged and left out.
+    pub fn bits(&self, names: &[&str]) -> u32 {
+        names.iter().fold(0, |bits, name| match self.get(name) {
+            Some(bit) => bits | bit,
+            None => {
+                log::error!("collision layer {} was never registered", name);
+                bits
+            },
+        })
+    }
+}
+
+impl Default for CollisionLayers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How a collider reacts when it overlaps another one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyKind {
+    /// Never moves
+    Static,
+    /// Moves by its own velocity but isn't pushed by anything
+    Kinematic,
+    /// Falls with gravity and gets pushed out of whatever it hits
+    Rigid,
+}
+
+impl Collider {
+    /// Every shape in world space for a collider at `at`, with whether it's a sensor.
+    /// The `boundary` comes first, followed by `shapes` in order.
+    pub fn outlines(&self, at: &Coordinates) -> Vec<(Convex, bool)> {
+        (0..=self.shapes.len()).map(|shape| self.outline_at(at, shape)).collect()
+    }
+
+    /// Smallest box around each of the `outlines`
+    pub fn boxes(&self, at: &Coordinates) -> Vec<(Aabb, bool)> {
+        self.outlines(at).into_iter().map(|(outline, sensor)| (outline.bounds(), sensor)).collect()
+    }
+
+    fn outline_at(&self, at: &Coordinates, shape: usize) -> (Convex, bool) {
+        let (boundary, outline, sensor) = match shape {
+            0 => (self.boundary, &self.outline, false),
+            shape => {
+                let shape = &self.shapes[shape - 1];
+                (shape.boundary, &shape.outline, shape.sensor)
+            },
+        };
+        let convex = match outline {
+            Outline::Rectangle => Convex::rectangle(world_box(at, boundary)),
+            Outline::Circle => {
+                let (x, y, width, height) = world_box(at, boundary);
+                Convex::Circle { center: (x + width / 2.0, y + height / 2.0), radius: width.min(height) / 2.0 }
+            },
+            Outline::Polygon(points) => Convex::Polygon(points.iter().map(|(x, y)| (at.coord_x + x, at.coord_y + y)).collect()),
+        };
+        (convex, sensor)
+    }
+
+    fn is_rectangle(&self, shape: usize) -> bool {
+        match shape {
+            0 => self.outline == Outline::Rectangle,
+            shape => self.shapes[shape - 1].outline == Outline::Rectangle,
+        }
+    }
+
+    /// Whether the two colliders are on layers the other one collides with
+    pub fn interacts_with(&self, other: &Collider) -> bool {
+        self.layers & other.mask != 0 && other.layers & self.mask != 0
+    }
+
+    pub fn kind(&self) -> BodyKind {
+        if self.rigid_body {
+            BodyKind::Rigid
+        } else if self.active {
+            BodyKind::Kinematic
+        } else {
+            BodyKind::Static
+        }
+    }
+}
+
+/// Downwards acceleration of rigid bodies in pixels per second squared
+pub const GRAVITY: f64 = 3600.0;
+
+// Most passes over the overlapping pairs per step
+const SOLVER_ITERATIONS: usize = 4;
+
+// How far from a sticky surface a clinging body can be and still hold on
+const CLING_REACH: f64 = 0.5;
+
+// How far below the top of a one-way collider a body can have been and still land on it
+const ONE_WAY_TOLERANCE: f64 = 0.01;
+
+/// Simulates `delta` seconds of physics, keeping the broadphase in step with the bodies.
+/// Velocities are in pixels per second.
 pub fn simulate_frame(
-    last_updated: &Instant,
-    colliders: &mut RefMut<Vec<Option<Collider>>>,
-    coordinates: &mut RefMut<Vec<Option<Coordinates>>>,
-    renderable_entities: &HashMap<i32,i32>,
-    camera: &Camera) {
-    let mut id_grid: [[Vec<usize>; 5]; 6] = Default::default();
-    let mut id_active = Vec::new();
-    
-    let zip = colliders.iter_mut().zip(coordinates.iter_mut());
-    let mut entities= zip.filter_map(|(health, name)| Some((health.as_mut()?, name.as_mut()?)));
-    
-    // puts colliders into a grid system
-    for (index, (collider, coordinate)) in  entities.borrow_mut().enumerate() {
-            let width = collider.boundary.2;
-            let height = collider.boundary.3;
-            
-            let x_rel = coordinate.coord_x as i32 - camera.x;
-            let y_rel = coordinate.coord_y as i32 - camera.y;
-            
-            let start_col = x_rel / 71;
-            let start_row = y_rel / 48;
-            
-            let end_col = (x_rel + width as i32) / 71;
-            let end_row = (y_rel + height as i32) / 48;
-            
-            for col in start_col..=end_col {
-                for row in start_row..=end_row {
-                    if col < 6 {
-                        id_grid[col as usize][row as usize].push(index);
+    delta: f64,
+    bodies: &mut Query<(&mut Coordinates, &mut Collider)>,
+    spatial: &mut SpatialHash,
+    contacts: &mut TriggerContacts,
+    collisions: &mut Vec<CollisionEvent>,
+    triggers: &mut Vec<TriggerEvent>) {
+    let mut ids: Vec<EntityId> = Vec::new();
+    let mut entities: Vec<(Mut<Coordinates>, Mut<Collider>)> = Vec::new();
+    for (id, (coordinate, collider)) in bodies.iter() {
+        ids.push(id);
+        entities.push((coordinate, collider));
+    }
+    let indices: HashMap<EntityId, usize> = ids.iter().enumerate().map(|(index, id)| (*id, index)).collect();
+
+    let previous_y: Vec<f64> = entities.iter().map(|(coordinates, _)| coordinates.coord_y).collect();
+
+    // clinging bodies hang on until they push away or whatever they held on to is gone
+    for index in 0..entities.len() {
+        if let Some(side) = entities[index].1.clinging {
+            if moving_away(&entities[index].1, side) || !touching_sticky(&entities, &indices, spatial, index, side) {
+                entities[index].1.clinging = None;
+            }
+        }
+    }
+
+    // performs gravity on rigid_bodies and applies velocity to active colliders
+    for (coordinate, collider) in entities.iter_mut() {
+        if collider.rigid_body && collider.clinging.is_none() {
+            collider.vel_y -= GRAVITY * delta;
+        }
+        if collider.active {
+            if !is_swept(collider) {
+                coordinate.coord_x += collider.vel_x * delta;
+                coordinate.coord_y += collider.vel_y * delta;
+            }
+            collider.grounded = collider.clinging.map(|side| Collision::on_side(side, 0.0));
+        }
+    }
+
+    // Only moving bodies and colliders something else moved or reshaped since the last step
+    // need their cells checked. Static tiles stay put, so they're left alone.
+    for (index, entity) in entities.iter().enumerate() {
+        if entity.1.active || entity.0.is_changed() || entity.1.is_changed() {
+            spatial.update(ids[index], bounds(entity));
+        }
+    }
+
+    // Continuous bodies move last, stopping at the first thing in their way.
+    // A second sweep slides them along whatever they hit.
+    let mut touched = HashSet::new();
+    for index in 0..entities.len() {
+        if !is_swept(&entities[index].1) {
+            continue;
+        }
+        let mut remaining = (entities[index].1.vel_x * delta, entities[index].1.vel_y * delta);
+        for _ in 0..2 {
+            let moving = sweepable(&entities[index]);
+            let mut hit: Option<(f64, Side, ShapeRef, ShapeRef)> = None;
+            for other in spatial.query_region(swept_region(bounds(&entities[index]), remaining)) {
+                let other = match indices.get(&other) {
+                    Some(&other) if other != index && entities[other].1.collision && entities[index].1.interacts_with(&entities[other].1) => other,
+                    _ => continue,
+                };
+                for &(shape, start) in moving.iter() {
+                    for (other_shape, other_box) in sweepable(&entities[other]) {
+                        if let Some((time, side)) = sweep(start, remaining, other_box) {
+                            if entities[other].1.one_way && !(side == Side::Down && landed(&entities, &previous_y, index, start, other, other_box)) {
+                                continue;
+                            }
+                            if hit.is_none_or(|(earliest, ..)| time < earliest) {
+                                hit = Some((time, side, (index, shape), (other, other_shape)));
+                            }
+                        }
                     }
                 }
             }
-            if collider.active {
-                id_active.push(index);
+
+            let other_sticky = hit.is_some_and(|(_, _, _, (other, _))| entities[other].1.sticky);
+            let (coordinates, collider) = &mut entities[index];
+            let (time, side, moved, other) = match hit {
+                Some(hit) => hit,
+                None => {
+                    coordinates.coord_x += remaining.0;
+                    coordinates.coord_y += remaining.1;
+                    break;
+                },
+            };
+            coordinates.coord_x += remaining.0 * time;
+            coordinates.coord_y += remaining.1 * time;
+            match side {
+                Side::Left | Side::Right => {
+                    collider.vel_x = 0.0;
+                    remaining = (0.0, remaining.1 * (1.0 - time));
+                },
+                Side::Down | Side::Up => {
+                    collider.vel_y = 0.0;
+                    remaining = (remaining.0 * (1.0 - time), 0.0);
+                },
+            }
+            let collision = Collision::on_side(side, 0.0);
+            touch(collider, collision, other_sticky);
+            if collider.clinging.is_some() {
+                remaining = (0.0, 0.0);
             }
+            report(collisions, &mut touched, &ids, moved, other, collision);
+        }
+        spatial.update(ids[index], bounds(&entities[index]));
     }
-    
-    let zip = coordinates.iter_mut().zip(colliders.iter_mut());
-    let mut entities: Vec<(&mut Coordinates, &mut Collider)>= zip.filter_map(|(health, name)| Some((health.as_mut()?, name.as_mut()?))).into_iter().collect();
 
-    // performs gravity on rigid_bodies and applies velocity to active colliders
-    for element in id_active {
-        let element = element.clone();
-            if entities[element].1.rigid_body {
-                entities[element].1.vel_y -= 0.0001;
+    // every pair of overlapping boxes where at least one of them moves, each pair only once
+    let mut pairs = Vec::new();
+    let mut seen = HashSet::new();
+    for (index, entity) in entities.iter().enumerate() {
+        if !entity.1.active {
+            continue;
+        }
+        for other in spatial.query_region(bounds(entity)) {
+            let other = match indices.get(&other) {
+                Some(&other) if other != index && entity.1.interacts_with(&entities[other].1) => other,
+                _ => continue,
+            };
+            let pair = (index.min(other), index.max(other));
+            if seen.insert(pair) {
+                pairs.push(pair);
             }
-            if entities[element].1.active {
-                entities[element].0.coord_x += entities[element].1.vel_x;
-                entities[element].0.coord_y += entities[element].1.vel_y;
+        }
+    }
+
+    // Pushing one body out can push it into another, as in a stack of crates,
+    // so keep going over the pairs until nothing overlaps or we run out of passes
+    for _ in 0..SOLVER_ITERATIONS {
+        let mut resolved_any = false;
+        for &(first, second) in pairs.iter() {
+            if !entities[first].1.collision || !entities[second].1.collision {
+                continue;
             }
-    }         
-
-    entities[0].1.grounded = None;
-    
-    // Checks if the player is colliding with any of the colliders in that grid square
-    for x in 0..6 {
-        for y in 0..5 {
-            if !id_grid[x][y].is_empty() && id_grid[x][y][0] == 0 {
-                for element in id_grid[x][y].iter() {
-                    if element.clone() != 0 as usize {
-                        let entity1:(&Coordinates, &Collider) = (entities[0].0, entities[0].1);
-                        let entity2:(&Coordinates, &Collider) = (entities[element.clone()].0, entities[element.clone()].1);
-                        match box_collision(entity1, entity2) {
-                            Some(Collision::Left) => {
-                                entities[0].0.coord_x = entity2.0.coord_x + entity2.1.boundary.2;
-                                entities[0].1.vel_x = 0.0;
-                                entities[0].1.grounded = Some(Collision::Left);
-                            },
-                            Some(Collision::Right) => {
-                                entities[0].0.coord_x = entity2.0.coord_x - entities[0].1.boundary.2;
-                                entities[0].1.vel_x = 0.0;
-                                entities[0].1.grounded = Some(Collision::Right);
-                            },
-                            Some(Collision::Up) => {
-                                entities[0].0.coord_y = entity2.0.coord_y - entities[0].1.boundary.3;
-                                entities[0].1.vel_y = 0.0;
-                                entities[0].1.grounded = Some(Collision::Up);
-                            },
-                            Some(Collision::Down) => {
-                                entities[0].0.coord_y = entity2.0.coord_y + entity2.1.boundary.3;
-                                entities[0].1.vel_y = 0.0;
-                                entities[0].1.grounded = Some(Collision::Down);
-                            },
-                            None => {
-
-                            },
-                        }
+            let shape_count = (entities[first].1.shapes.len() + 1, entities[second].1.shapes.len() + 1);
+            for shape in 0..shape_count.0 {
+                for other_shape in 0..shape_count.1 {
+                    // outlines are looked up fresh since an earlier shape may have pushed the bodies
+                    let (outline1, sensor1) = entities[first].1.outline_at(&entities[first].0, shape);
+                    let (outline2, sensor2) = entities[second].1.outline_at(&entities[second].0, other_shape);
+                    let collision = geometry::separate(&outline1, &outline2)
+                        .map(|(normal, depth)| Collision::new(normal, depth))
+                        .and_then(|collision| one_way_collision(&entities, &previous_y, (first, outline1.bounds()), (second, outline2.bounds()), collision));
+                    let collision = match collision {
+                        Some(collision) => collision,
+                        None => continue,
+                    };
+                    if !sensor1 && !sensor2 && resolve_collision(&mut entities, first, second, collision) {
+                        resolved_any = true;
+                        spatial.update(ids[first], bounds(&entities[first]));
+                        spatial.update(ids[second], bounds(&entities[second]));
                     }
+                    report(collisions, &mut touched, &ids, (first, shape), (second, other_shape), collision);
                 }
             }
         }
+        if !resolved_any {
+            break;
+        }
+    }
+
+    // triggers only report what's inside them, going by the solid shapes of the other entity
+    let mut overlapping = HashSet::new();
+    for &(first, second) in pairs.iter() {
+        let (trigger, other) = match (entities[first].1.collision, entities[second].1.collision) {
+            (false, true) => (first, second),
+            (true, false) => (second, first),
+            _ => continue,
+        };
+        let inside = outlines(&entities[trigger]).into_iter().any(|(volume, _)| {
+            outlines(&entities[other]).into_iter().any(|(body, sensor)| !sensor && geometry::separate(&volume, &body).is_some())
+        });
+        if inside {
+            let pair = (ids[trigger], ids[other]);
+            let state = if contacts.0.contains(&pair) { TriggerState::Stay } else { TriggerState::Enter };
+            triggers.push(TriggerEvent { trigger: pair.0, other: pair.1, state });
+            overlapping.insert(pair);
+        }
+    }
+    for &(trigger, other) in contacts.0.difference(&overlapping) {
+        triggers.push(TriggerEvent { trigger, other, state: TriggerState::Exit });
+    }
+    contacts.0 = overlapping;
+
+    for (_, collider) in entities.iter_mut() {
+        if collider.drop_through {
+            collider.drop_through = false;
+        }
     }
 }
 
-// checks if two boxes are colliding, and returns the side they are colliding on if true
-fn box_collision(entity1: (&Coordinates, &Collider), entity2: (&Coordinates, &Collider)) -> Option<Collision>{
-    if  entity1.0.coord_x < entity2.0.coord_x + entity2.1.boundary.2 &&
-        entity1.0.coord_x + entity1.1.boundary.2 > entity2.0.coord_x &&
-        entity1.0.coord_y < entity2.0.coord_y + entity2.1.boundary.3 &&
-        entity1.0.coord_y + entity1.1.boundary.3 > entity2.0.coord_y {
-        
-        let player_half_w = entity1.1.boundary.2/2.0;
-        let player_half_h = entity1.1.boundary.3/2.0;
-        let object_half_w = entity2.1.boundary.2/2.0;
-        let object_half_h = entity2.1.boundary.3/2.0;
-        let player_center_x =  entity1.0.coord_x + player_half_w;
-        let player_center_y =  entity1.0.coord_y + player_half_h;
-        let object_center_x = entity2.0.coord_x + object_half_w;
-        let object_center_y = entity2.0.coord_y + object_half_h;
-                            
-        let diff_x = player_center_x - object_center_x;
-        let diff_y = player_center_y - object_center_y;
-                            
-        let min_x_distance = player_half_w + object_half_w;
-        let min_y_distance = player_half_h + object_half_h;
-                            
-        let depth_x = if diff_x > 0.0 {min_x_distance - diff_x} else {-min_x_distance - diff_x};
-        let depth_y = if diff_y > 0.0 {min_y_distance - diff_y} else {-min_y_distance - diff_y};
-                            
-        if depth_x != 0.0 && depth_x != 0.0 {
-            if depth_x.abs() < depth_y.abs() {          
-                if depth_x > 0.0 {
-                    return Some(Collision::Left)
-                } else {
-                    return Some(Collision::Right)
-                }
-            } else {
-                if depth_y > 0.0 {
-                    return Some(Collision::Down)
-                } else {
-                    return Some(Collision::Up)
-                }
+fn is_swept(collider: &Collider) -> bool {
+    collider.continuous && collider.collision && collider.active
+}
+
+// area covered by a box over the whole of its move
+fn swept_region((x, y, width, height): Aabb, (dx, dy): (f64, f64)) -> Aabb {
+    (x + dx.min(0.0), y + dy.min(0.0), width + dx.abs(), height + dy.abs())
+}
+
+// Solid rectangles with their shape numbers. They're the only shapes that get swept,
+// anything else is left to the overlap checks.
+fn sweepable((coordinates, collider): &(Mut<Coordinates>, Mut<Collider>)) -> Vec<(usize, Aabb)> {
+    collider
+        .boxes(coordinates)
+        .into_iter()
+        .enumerate()
+        .filter(|&(shape, (_, sensor))| !sensor && collider.is_rectangle(shape))
+        .map(|(shape, (bounds, _))| (shape, bounds))
+        .collect()
+}
+
+// Time of impact as a fraction of the move, and the side of the moving box that hits.
+// Boxes that already overlap are left to the overlap check.
+fn sweep(moving: Aabb, (dx, dy): (f64, f64), other: Aabb) -> Option<(f64, Side)> {
+    let (x_entry, x_exit) = axis_times(moving.0, moving.2, dx, other.0, other.2);
+    let (y_entry, y_exit) = axis_times(moving.1, moving.3, dy, other.1, other.3);
+    let entry = x_entry.max(y_entry);
+    let exit = x_exit.min(y_exit);
+    if entry >= exit || !(0.0..=1.0).contains(&entry) {
+        return None;
+    }
+
+    let side = if x_entry > y_entry {
+        if dx > 0.0 { Side::Right } else { Side::Left }
+    } else {
+        if dy < 0.0 { Side::Down } else { Side::Up }
+    };
+    Some((entry, side))
+}
+
+// when along the move the two boxes start and stop overlapping on one axis
+fn axis_times(start: f64, size: f64, distance: f64, other_start: f64, other_size: f64) -> (f64, f64) {
+    if distance > 0.0 {
+        ((other_start - (start + size)) / distance, (other_start + other_size - start) / distance)
+    } else if distance < 0.0 {
+        ((other_start + other_size - start) / distance, (other_start - (start + size)) / distance)
+    } else if start < other_start + other_size && start + size > other_start {
+        (f64::NEG_INFINITY, f64::INFINITY)
+    } else {
+        (f64::INFINITY, f64::NEG_INFINITY)
+    }
+}
+
+/// Box in world space as x, y, width, height for a boundary given as x1, y1, x2, y2 relative to `at`
+pub fn world_box(at: &Coordinates, boundary: (f64, f64, f64, f64)) -> Aabb {
+    (at.coord_x + boundary.0, at.coord_y + boundary.1, boundary.2 - boundary.0, boundary.3 - boundary.1)
+}
+
+fn outlines((coordinates, collider): &(Mut<Coordinates>, Mut<Collider>)) -> Vec<(Convex, bool)> {
+    collider.outlines(coordinates)
+}
+
+fn boxes((coordinates, collider): &(Mut<Coordinates>, Mut<Collider>)) -> Vec<(Aabb, bool)> {
+    collider.boxes(coordinates)
+}
+
+// smallest box around every shape, which is what the broadphase stores
+fn bounds(entity: &(Mut<Coordinates>, Mut<Collider>)) -> Aabb {
+    let boxes = boxes(entity);
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY);
+    for ((x, y, width, height), _) in boxes {
+        min_x = min_x.min(x);
+        min_y = min_y.min(y);
+        max_x = max_x.max(x + width);
+        max_y = max_y.max(y + height);
+    }
+    (min_x, min_y, max_x - min_x, max_y - min_y)
+}
+
+// Sends a collision to both entities, once per pair of shapes each step
+fn report(
+    collisions: &mut Vec<CollisionEvent>,
+    touched: &mut HashSet<(ShapeRef, ShapeRef)>,
+    ids: &[EntityId],
+    first: ShapeRef,
+    second: ShapeRef,
+    collision: Collision) {
+    let key = if first.0 < second.0 { (first, second) } else { (second, first) };
+    if touched.insert(key) {
+        collisions.push(CollisionEvent { entity: ids[first.0], other: ids[second.0], collision, shape: first.1, other_shape: second.1 });
+        collisions.push(CollisionEvent { entity: ids[second.0], other: ids[first.0], collision: collision.flipped(), shape: second.1, other_shape: first.1 });
+    }
+}
+
+// Pushes two overlapping bodies apart, where the collision is as the first body sees it.
+// Rigid bodies are pushed out of anything else, and two rigid bodies split the push between them.
+// Returns false if neither body can be pushed.
+fn resolve_collision(entities: &mut [(Mut<Coordinates>, Mut<Collider>)], first: usize, second: usize, collision: Collision) -> bool {
+    let (first_share, second_share) = match (entities[first].1.kind(), entities[second].1.kind()) {
+        (BodyKind::Rigid, BodyKind::Rigid) => (0.5, 0.5),
+        (BodyKind::Rigid, _) => (1.0, 0.0),
+        (_, BodyKind::Rigid) => (0.0, 1.0),
+        _ => return false,
+    };
+    let sticky = (entities[first].1.sticky, entities[second].1.sticky);
+
+    for (index, share, collision, other_sticky) in [(first, first_share, collision, sticky.1), (second, second_share, collision.flipped(), sticky.0)] {
+        if share == 0.0 {
+            continue;
+        }
+        let (coordinates, collider) = &mut entities[index];
+        let (normal, depth) = (collision.normal, collision.depth * share);
+        if collision.side == Side::Down {
+            // Whatever a body stands on pushes it straight up, so it can walk up
+            // slopes at full speed and doesn't slide down them when standing still
+            coordinates.coord_y += depth / normal.1;
+            if collider.vel_y < 0.0 {
+                collider.vel_y = 0.0;
             }
         } else {
-            println!("COLLISION ERROR");
+            coordinates.coord_x += normal.0 * depth;
+            coordinates.coord_y += normal.1 * depth;
+            // only the part of the velocity heading into the surface is stopped
+            let into = collider.vel_x * normal.0 + collider.vel_y * normal.1;
+            if into < 0.0 {
+                collider.vel_x -= into * normal.0;
+                collider.vel_y -= into * normal.1;
+            }
+        }
+        touch(collider, collision, other_sticky);
+    }
+    true
+}
+
+// Marks a body as touching something, grabbing hold if that something is sticky
+fn touch(collider: &mut Collider, collision: Collision, sticky: bool) {
+    // standing on something wins over touching it from the side, so jumps still work
+    if collider.grounded.is_none_or(|grounded| grounded.side != Side::Down) {
+        collider.grounded = Some(collision);
+    }
+    if sticky && collider.rigid_body {
+        collider.clinging = Some(collision.side);
+        // Nothing moves it into the surface. Walls also hold it where it grabbed on,
+        // while floors and ceilings let it keep running along them.
+        let into = collider.vel_x * collision.normal.0 + collider.vel_y * collision.normal.1;
+        if into < 0.0 {
+            collider.vel_x -= into * collision.normal.0;
+            collider.vel_y -= into * collision.normal.1;
+        }
+        if matches!(collision.side, Side::Left | Side::Right) {
+            collider.vel_y = 0.0;
         }
     }
-    return None;
-}
\ No newline at end of file
+}
+
+// One-way colliders only stop bodies that were above them on the last step, and always push
+// those back up. Returns the collision to resolve, or None if the shapes pass through each other.
+fn one_way_collision(
+    entities: &[(Mut<Coordinates>, Mut<Collider>)],
+    previous_y: &[f64],
+    (first, box1): (usize, Aabb),
+    (second, box2): (usize, Aabb),
+    collision: Collision) -> Option<Collision> {
+    match (entities[first].1.one_way, entities[second].1.one_way) {
+        (false, true) => landed(entities, previous_y, first, box1, second, box2)
+            .then(|| Collision::on_side(Side::Down, box2.1 + box2.3 - box1.1)),
+        (true, false) => landed(entities, previous_y, second, box2, first, box1)
+            .then(|| Collision::on_side(Side::Up, box1.1 + box1.3 - box2.1)),
+        _ => Some(collision),
+    }
+}
+
+// whether the body's box was resting on or above the platform's box at the start of the step
+fn landed(entities: &[(Mut<Coordinates>, Mut<Collider>)], previous_y: &[f64], body: usize, body_box: Aabb, platform: usize, platform_box: Aabb) -> bool {
+    if entities[body].1.drop_through {
+        return false;
+    }
+    let bottom = body_box.1 - (entities[body].0.coord_y - previous_y[body]);
+    let top = platform_box.1 + platform_box.3 - (entities[platform].0.coord_y - previous_y[platform]);
+    bottom >= top - ONE_WAY_TOLERANCE
+}
+
+fn moving_away(collider: &Collider, side: Side) -> bool {
+    match side {
+        Side::Left => collider.vel_x > 0.0,
+        Side::Right => collider.vel_x < 0.0,
+        Side::Down => collider.vel_y > 0.0,
+        Side::Up => collider.vel_y < 0.0,
+    }
+}
+
+// checks for a sticky collider right up against the body's side
+fn touching_sticky(
+    entities: &[(Mut<Coordinates>, Mut<Collider>)],
+    indices: &HashMap<EntityId, usize>,
+    spatial: &SpatialHash,
+    index: usize,
+    side: Side) -> bool {
+    let reach = |(x, y, width, height): Aabb| match side {
+        Side::Left => (x - CLING_REACH, y, width + CLING_REACH, height),
+        Side::Right => (x, y, width + CLING_REACH, height),
+        Side::Down => (x, y - CLING_REACH, width, height + CLING_REACH),
+        Side::Up => (x, y, width, height + CLING_REACH),
+    };
+    let probes: Vec<Aabb> = boxes(&entities[index]).into_iter().filter(|(_, sensor)| !sensor).map(|(body, _)| reach(body)).collect();
+
+    spatial.query_region(reach(bounds(&entities[index]))).into_iter().any(|other| match indices.get(&other) {
+        Some(&other) if other != index && entities[other].1.sticky && entities[other].1.collision && entities[index].1.interacts_with(&entities[other].1) => boxes(&entities[other])
+            .into_iter()
+            .any(|(surface, sensor)| !sensor && probes.iter().any(|&probe| overlaps(probe, surface))),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{default_schedule, Shape, World};
+
+    const STEP: f64 = 1.0 / 60.0;
+
+    fn spawn(world: &mut World, (x, y): (f64, f64), collider: Collider) -> EntityId {
+        let entity = world.new_entity();
+        world.add_component_to_entity(entity, Coordinates { coord_x: x, coord_y: y });
+        world.add_component_to_entity(entity, collider);
+        entity
+    }
+
+    fn rigid(boundary: Aabb) -> Collider {
+        Collider { rigid_body: true, active: true, boundary, ..Default::default() }
+    }
+
+    // runs one physics step and returns the collisions it sent
+    fn step(world: &World) -> Vec<CollisionEvent> {
+        let mut bodies = world.query::<(&mut Coordinates, &mut Collider)>();
+        let mut spatial = world.resource_mut::<SpatialHash>().unwrap();
+        let mut contacts = world.resource_mut::<TriggerContacts>().unwrap();
+        let (mut collisions, mut triggers) = (Vec::new(), Vec::new());
+        simulate_frame(STEP, &mut bodies, &mut spatial, &mut contacts, &mut collisions, &mut triggers);
+        collisions
+    }
+
+    fn position(world: &World, entity: EntityId) -> (f64, f64) {
+        let mut query = world.query::<(&Coordinates,)>();
+        let (at,) = query.get(entity).unwrap();
+        (at.coord_x, at.coord_y)
+    }
+
+    fn collider(world: &World, entity: EntityId) -> Collider {
+        world.query::<(&Collider,)>().get(entity).unwrap().0.clone()
+    }
+
+    fn assert_near((x, y): (f64, f64), (expected_x, expected_y): (f64, f64)) {
+        assert!((x - expected_x).abs() < 1e-9 && (y - expected_y).abs() < 1e-9, "({}, {}) isn't ({}, {})", x, y, expected_x, expected_y);
+    }
+
+    #[test]
+    fn spatial_hash_only_follows_colliders_that_changed() {
+        let mut world = World::new();
+        let tile = world.new_entity();
+        world.add_component_to_entity(tile, Coordinates { coord_x: 0.0, coord_y: 0.0 });
+        world.add_component_to_entity(tile, Collider { boundary: (0.0, 0.0, 16.0, 16.0), ..Default::default() });
+        let mut schedule = default_schedule();
+        schedule.run(&mut world);
+        assert_eq!(world.resource::<SpatialHash>().unwrap().bounds(tile), Some((0.0, 0.0, 16.0, 16.0)));
+
+        // an untouched static collider isn't looked at again
+        world.resource_mut::<SpatialHash>().unwrap().update(tile, (1.0, 1.0, 1.0, 1.0));
+        schedule.run(&mut world);
+        assert_eq!(world.resource::<SpatialHash>().unwrap().bounds(tile), Some((1.0, 1.0, 1.0, 1.0)));
+
+        // but one that was moved is
+        world.query::<(&mut Coordinates,)>().get(tile).unwrap().0.coord_x = 100.0;
+        schedule.run(&mut world);
+        assert_eq!(world.resource::<SpatialHash>().unwrap().bounds(tile), Some((100.0, 0.0, 16.0, 16.0)));
+    }
+
+    #[test]
+    fn sticky_contact_only_stops_movement_into_the_surface() {
+        let mut world = World::new();
+        let floor = world.new_entity();
+        world.add_component_to_entity(floor, Coordinates { coord_x: 0.0, coord_y: 0.0 });
+        world.add_component_to_entity(floor, Collider { sticky: true, boundary: (0.0, 0.0, 160.0, 16.0), ..Default::default() });
+        let body = world.new_entity();
+        world.add_component_to_entity(body, Coordinates { coord_x: 10.0, coord_y: 15.0 });
+        world.add_component_to_entity(body, Collider {
+            rigid_body: true,
+            active: true,
+            boundary: (0.0, 0.0, 16.0, 16.0),
+            vel_x: 120.0,
+            vel_y: -60.0,
+            ..Default::default()
+        });
+        default_schedule().run(&mut world);
+
+        let mut query = world.query::<(&Collider,)>();
+        let (collider,) = query.get(body).unwrap();
+        assert_eq!(collider.clinging, Some(Side::Down));
+        assert_eq!((collider.vel_x, collider.vel_y), (120.0, 0.0));
+    }
+
+    #[test]
+    fn sticky_walls_hold_bodies_in_place() {
+        let mut world = World::new();
+        spawn(&mut world, (100.0, 0.0), Collider { sticky: true, boundary: (0.0, 0.0, 16.0, 400.0), ..Default::default() });
+        let body = spawn(&mut world, (83.0, 200.0), Collider { vel_x: 120.0, vel_y: -180.0, ..rigid((0.0, 0.0, 16.0, 16.0)) });
+        step(&world);
+        assert_eq!(collider(&world, body).clinging, Some(Side::Right));
+        assert_eq!((collider(&world, body).vel_x, collider(&world, body).vel_y), (0.0, 0.0));
+
+        let held = position(&world, body);
+        assert_eq!(held.0, 84.0);
+        for _ in 0..30 {
+            step(&world);
+        }
+        assert_eq!(position(&world, body), held);
+        assert_eq!(collider(&world, body).clinging, Some(Side::Right));
+    }
+
+    #[test]
+    fn rigid_bodies_are_pushed_out_of_static_and_kinematic_colliders() {
+        let mut world = World::new();
+        let floor = spawn(&mut world, (0.0, 0.0), Collider { boundary: (0.0, 0.0, 64.0, 16.0), ..Default::default() });
+        let belt = spawn(&mut world, (100.0, 0.0), Collider { active: true, vel_x: 60.0, boundary: (0.0, 0.0, 64.0, 16.0), ..Default::default() });
+        let on_floor = spawn(&mut world, (10.0, 14.0), rigid((0.0, 0.0, 16.0, 16.0)));
+        let on_belt = spawn(&mut world, (110.0, 14.0), rigid((0.0, 0.0, 16.0, 16.0)));
+        step(&world);
+
+        // gravity pulls them a pixel further in, and all of it is pushed back out
+        assert_near(position(&world, on_floor), (10.0, 16.0));
+        assert_near(position(&world, on_belt), (110.0, 16.0));
+        assert_eq!(collider(&world, on_floor).vel_y, 0.0);
+        assert_eq!(collider(&world, on_floor).grounded.map(|grounded| grounded.side), Some(Side::Down));
+        // neither of them gives way
+        assert_near(position(&world, floor), (0.0, 0.0));
+        assert_near(position(&world, belt), (101.0, 0.0));
+    }
+
+    #[test]
+    fn rigid_pairs_split_the_push() {
+        let mut world = World::new();
+        let left = spawn(&mut world, (0.0, 100.0), rigid((0.0, 0.0, 16.0, 16.0)));
+        let right = spawn(&mut world, (12.0, 100.0), rigid((0.0, 0.0, 16.0, 16.0)));
+        let collisions = step(&world);
+
+        assert_near(position(&world, left), (-2.0, 99.0));
+        assert_near(position(&world, right), (14.0, 99.0));
+        let sides: Vec<(EntityId, Side)> = collisions.iter().map(|event| (event.entity, event.collision.side)).collect();
+        assert_eq!(sides, [(left, Side::Right), (right, Side::Left)]);
+    }
+
+    #[test]
+    fn static_and_kinematic_pairs_are_left_overlapping() {
+        let mut world = World::new();
+        let wall = spawn(&mut world, (0.0, 0.0), Collider { boundary: (0.0, 0.0, 16.0, 16.0), ..Default::default() });
+        let other_wall = spawn(&mut world, (8.0, 0.0), Collider { boundary: (0.0, 0.0, 16.0, 16.0), ..Default::default() });
+        let platform = spawn(&mut world, (4.0, 4.0), Collider { active: true, boundary: (0.0, 0.0, 16.0, 16.0), ..Default::default() });
+        let other_platform = spawn(&mut world, (6.0, 6.0), Collider { active: true, boundary: (0.0, 0.0, 16.0, 16.0), ..Default::default() });
+        let collisions = step(&world);
+
+        assert_near(position(&world, wall), (0.0, 0.0));
+        assert_near(position(&world, other_wall), (8.0, 0.0));
+        assert_near(position(&world, platform), (4.0, 4.0));
+        assert_near(position(&world, other_platform), (6.0, 6.0));
+        // they still hear about touching, as long as one of them moves
+        assert!(collisions.iter().any(|event| event.entity == platform && event.other == other_platform));
+        assert!(!collisions.iter().any(|event| event.entity == wall && event.other == other_wall));
+    }
+
+    #[test]
+    fn boundaries_are_offset_from_the_coordinates() {
+        let mut world = World::new();
+        spawn(&mut world, (18.0, 0.0), Collider { boundary: (0.0, 0.0, 16.0, 400.0), ..Default::default() });
+        // the box spans 4 to 20, so it overlaps the wall by 2
+        let body = spawn(&mut world, (0.0, 100.0), rigid((4.0, 0.0, 20.0, 16.0)));
+        step(&world);
+        assert_near(position(&world, body), (-2.0, 99.0));
+        assert_eq!(collider(&world, body).outlines(&Coordinates { coord_x: 0.0, coord_y: 0.0 })[0].0.bounds(), (4.0, 0.0, 16.0, 16.0));
+    }
+
+    #[test]
+    fn extra_shapes_collide_unless_they_are_sensors() {
+        let mut world = World::new();
+        let wall = spawn(&mut world, (22.0, 0.0), Collider { boundary: (0.0, 0.0, 16.0, 400.0), ..Default::default() });
+        let nose = |sensor| Shape { boundary: (16.0, 4.0, 24.0, 12.0), outline: Outline::Rectangle, sensor };
+        let solid = spawn(&mut world, (0.0, 100.0), Collider { shapes: vec![nose(false)], ..rigid((0.0, 0.0, 16.0, 16.0)) });
+        let sensing = spawn(&mut world, (0.0, 300.0), Collider { shapes: vec![nose(true)], ..rigid((0.0, 0.0, 16.0, 16.0)) });
+        let collisions = step(&world);
+
+        assert_near(position(&world, solid), (-2.0, 99.0));
+        assert_near(position(&world, sensing), (0.0, 299.0));
+        // both report which shape touched
+        for entity in [solid, sensing] {
+            assert!(collisions.iter().any(|event| event.entity == entity && event.other == wall && event.shape == 1 && event.other_shape == 0));
+        }
+    }
+
+    #[test]
+    fn continuous_bodies_stop_at_thin_walls() {
+        let mut world = World::new();
+        let wall = spawn(&mut world, (100.0, 0.0), Collider { boundary: (0.0, 0.0, 2.0, 400.0), ..Default::default() });
+        // 200 pixels a step, far more than the wall is thick
+        let body = spawn(&mut world, (0.0, 100.0), Collider { continuous: true, vel_x: 12000.0, ..rigid((0.0, 0.0, 16.0, 16.0)) });
+        let projectile = spawn(&mut world, (0.0, 200.0), Collider { active: true, continuous: true, vel_x: 12000.0, boundary: (0.0, 0.0, 4.0, 4.0), ..Default::default() });
+        let collisions = step(&world);
+
+        // the rigid body slides down the wall for the rest of the step
+        assert_near(position(&world, body), (84.0, 99.0));
+        assert_near(position(&world, projectile), (96.0, 200.0));
+        for entity in [body, projectile] {
+            assert_eq!(collider(&world, entity).vel_x, 0.0);
+            assert!(collisions.iter().any(|event| event.entity == entity && event.other == wall && event.collision.side == Side::Right));
+        }
+    }
+
+    #[test]
+    fn continuous_bodies_that_hit_nothing_make_their_whole_move() {
+        let mut world = World::new();
+        let body = spawn(&mut world, (0.0, 100.0), Collider { continuous: true, vel_x: 600.0, ..rigid((0.0, 0.0, 16.0, 16.0)) });
+        let projectile = spawn(&mut world, (0.0, 200.0), Collider { active: true, continuous: true, vel_x: 600.0, vel_y: 300.0, boundary: (0.0, 0.0, 4.0, 4.0), ..Default::default() });
+        assert!(step(&world).is_empty());
+
+        assert_near(position(&world, body), (10.0, 99.0));
+        assert_near(position(&world, projectile), (10.0, 205.0));
+        assert_eq!(collider(&world, projectile).vel_x, 600.0);
+    }
+
+    #[test]
+    fn continuous_bodies_land_on_one_way_platforms_only_from_above() {
+        let mut world = World::new();
+        spawn(&mut world, (0.0, 0.0), Collider { one_way: true, boundary: (0.0, 0.0, 64.0, 16.0), ..Default::default() });
+        let falling = spawn(&mut world, (0.0, 40.0), Collider { continuous: true, vel_y: -6000.0, ..rigid((0.0, 0.0, 16.0, 16.0)) });
+        let rising = spawn(&mut world, (40.0, -40.0), Collider { continuous: true, vel_y: 6060.0, ..rigid((0.0, 0.0, 16.0, 16.0)) });
+        step(&world);
+
+        assert_near(position(&world, falling), (0.0, 16.0));
+        assert_eq!(collider(&world, falling).grounded.map(|grounded| grounded.side), Some(Side::Down));
+        assert_near(position(&world, rising), (40.0, 60.0));
+        assert_eq!(collider(&world, rising).grounded, None);
+    }
+
+    #[test]
+    fn one_way_platforms_stop_bodies_landing_from_above() {
+        let mut world = World::new();
+        let platform = spawn(&mut world, (0.0, 0.0), Collider { one_way: true, boundary: (0.0, 0.0, 64.0, 16.0), ..Default::default() });
+        let body = spawn(&mut world, (0.0, 17.0), Collider { vel_y: -120.0, ..rigid((0.0, 0.0, 16.0, 16.0)) });
+        let collisions = step(&world);
+
+        assert_near(position(&world, body), (0.0, 16.0));
+        assert_eq!(collider(&world, body).grounded.map(|grounded| grounded.side), Some(Side::Down));
+        assert!(collisions.iter().any(|event| event.entity == body && event.other == platform));
+    }
+
+    #[test]
+    fn one_way_platforms_let_bodies_through_from_below() {
+        let mut world = World::new();
+        spawn(&mut world, (0.0, 0.0), Collider { one_way: true, boundary: (0.0, 0.0, 64.0, 16.0), ..Default::default() });
+        let body = spawn(&mut world, (0.0, -10.0), Collider { vel_y: 600.0, ..rigid((0.0, 0.0, 16.0, 16.0)) });
+        assert!(step(&world).is_empty());
+        assert_near(position(&world, body), (0.0, -1.0));
+        assert_eq!(collider(&world, body).grounded, None);
+    }
+
+    #[test]
+    fn dropping_through_skips_one_way_platforms_for_a_step() {
+        let mut world = World::new();
+        spawn(&mut world, (0.0, 0.0), Collider { one_way: true, boundary: (0.0, 0.0, 64.0, 16.0), ..Default::default() });
+        let body = spawn(&mut world, (0.0, 16.0), Collider { drop_through: true, ..rigid((0.0, 0.0, 16.0, 16.0)) });
+        step(&world);
+        assert_near(position(&world, body), (0.0, 15.0));
+        assert!(!collider(&world, body).drop_through);
+
+        // once it's below the top the platform doesn't catch it again
+        step(&world);
+        assert_near(position(&world, body), (0.0, 13.0));
+    }
+
+    #[test]
+    fn layers_only_collide_when_each_is_in_the_others_mask() {
+        let mut world = World::new();
+        let mut layers = CollisionLayers::new();
+        let (terrain, ghost) = (layers.register("terrain"), layers.register("ghost"));
+        let floor = |x| Collider { layers: terrain, mask: !ghost, boundary: (x, 0.0, x + 32.0, 16.0), ..Default::default() };
+        spawn(&mut world, (0.0, 0.0), floor(0.0));
+        spawn(&mut world, (0.0, 0.0), floor(100.0));
+        spawn(&mut world, (0.0, 0.0), floor(200.0));
+        let landing = spawn(&mut world, (0.0, 14.0), Collider { mask: terrain, ..rigid((0.0, 0.0, 16.0, 16.0)) });
+        // the floor doesn't look for ghosts
+        let ghost_body = spawn(&mut world, (100.0, 14.0), Collider { layers: ghost, mask: terrain, ..rigid((0.0, 0.0, 16.0, 16.0)) });
+        // and this body doesn't look for terrain
+        let blind = spawn(&mut world, (200.0, 14.0), Collider { mask: !terrain, ..rigid((0.0, 0.0, 16.0, 16.0)) });
+        let collisions = step(&world);
+
+        assert_near(position(&world, landing), (0.0, 16.0));
+        assert_near(position(&world, ghost_body), (100.0, 13.0));
+        assert_near(position(&world, blind), (200.0, 13.0));
+        assert!(collisions.iter().all(|event| event.entity != ghost_body && event.entity != blind));
+    }
+
+    fn slope() -> Collider {
+        Collider { outline: Outline::Polygon(vec![(0.0, 0.0), (64.0, 0.0), (64.0, 64.0)]), ..Default::default() }
+    }
+
+    #[test]
+    fn bodies_walk_up_slopes_at_full_speed() {
+        let mut world = World::new();
+        spawn(&mut world, (0.0, 0.0), slope());
+        // resting on its bottom right corner
+        let body = spawn(&mut world, (10.0, 26.0), Collider { vel_x: 60.0, ..rigid((0.0, 0.0, 16.0, 16.0)) });
+        for _ in 0..10 {
+            step(&world);
+        }
+        assert_near(position(&world, body), (20.0, 36.0));
+        assert_eq!(collider(&world, body).vel_x, 60.0);
+        assert_eq!(collider(&world, body).grounded.map(|grounded| grounded.side), Some(Side::Down));
+    }
+
+    #[test]
+    fn circles_rest_on_slopes_without_sliding() {
+        let mut world = World::new();
+        spawn(&mut world, (0.0, 0.0), slope());
+        let ball = spawn(&mut world, (20.0, 31.0), Collider { outline: Outline::Circle, ..rigid((0.0, 0.0, 16.0, 16.0)) });
+        for _ in 0..30 {
+            step(&world);
+        }
+        // touching the slope, with the center 8 pixels from it
+        assert_near(position(&world, ball), (20.0, 20.0 + 8.0 * std::f64::consts::SQRT_2));
+        assert_eq!(collider(&world, ball).grounded.map(|grounded| grounded.side), Some(Side::Down));
+    }
+}
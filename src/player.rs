@@ -0,0 +1,147 @@
+use crate::input::{ActionEvent, GameInput, InputState, MousePosition};
+use crate::query::Changed;
+use crate::{Camera, Collider, Coordinates, EntityId, Time, World};
+use serde::{Deserialize, Serialize};
+
+// Pull in pixels per second squared for every pixel between a grappled entity and its anchor.
+// Vertical pull is stronger so swinging doesn't just drag the entity along the ground.
+const GRAPPLE_PULL_X: f64 = 72.0;
+const GRAPPLE_PULL_Y: f64 = 100.8;
+
+/// Hit points. A `DeathEvent` is sent when they run out.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Health {
+    pub current: i32,
+    pub max: i32,
+}
+
+impl Health {
+    pub fn damage(&mut self, amount: i32) {
+        self.current = (self.current - amount).max(0);
+    }
+
+    pub fn heal(&mut self, amount: i32) {
+        self.current = (self.current + amount).min(self.max);
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.current <= 0
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self { current: 100, max: 100 }
+    }
+}
+
+/// Sent once when an entity's health drops to zero
+#[derive(Debug, Clone)]
+pub struct DeathEvent {
+    pub entity: EntityId,
+}
+
+/// Added by `check_health` when an entity's `DeathEvent` is sent, and taken off again if it's healed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct Dead;
+
+/// Grappling hook aimed at the cursor.
+/// While attached, the entity's collider is pulled towards the anchor.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Grapple {
+    pub anchor: Option<(f64, f64)>,
+}
+
+/// Attaches every grapple to the world position under the cursor when the
+/// grapple action is pressed, or lets go if it was already attached
+pub fn fire_grapples(world: &World) {
+    let fired = world
+        .read::<ActionEvent>()
+        .iter()
+        .any(|event| event.action == GameInput::PlayerGrapple && event.state == InputState::Pressed);
+    if !fired {
+        return;
+    }
+
+    let camera = world.resource::<Camera>().unwrap();
+    let mouse = world.resource::<MousePosition>().unwrap();
+    let target = ((camera.x + mouse.x) as f64, (camera.y + mouse.y) as f64);
+    for (_, (mut grapple,)) in world.query::<(&mut Grapple,)>().iter() {
+        grapple.anchor = match grapple.anchor {
+            Some(_) => None,
+            None => Some(target),
+        };
+    }
+}
+
+/// Pulls attached entities towards their anchor like a spring
+pub fn pull_grapples(world: &World) {
+    let delta = world.resource::<Time>().unwrap().delta();
+    let mut grappled = world.query::<(&Grapple, &Coordinates, &mut Collider)>();
+    for (_, (grapple, coordinates, mut collider)) in grappled.iter() {
+        if let Some((anchor_x, anchor_y)) = grapple.anchor {
+            collider.vel_x -= (coordinates.coord_x - anchor_x) * GRAPPLE_PULL_X * delta;
+            collider.vel_y -= (coordinates.coord_y - anchor_y) * GRAPPLE_PULL_Y * delta;
+        }
+    }
+}
+
+/// Sends a `DeathEvent` for every entity whose health changed to zero since the last check.
+/// Entities that are already `Dead` aren't reported again until they've been healed.
+pub fn check_health(world: &World) {
+    let mut changed = world.query_filtered::<(&Health, Option<&Dead>), Changed<Health>>();
+    for (entity, (health, dead)) in changed.iter() {
+        match (health.is_dead(), dead.is_some()) {
+            (true, false) => {
+                world.send(DeathEvent { entity });
+                world.commands().insert(entity, Dead);
+            },
+            (false, true) => world.commands().remove::<Dead>(entity),
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::{Schedule, Stage};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn deaths_are_reported_once_until_healed() {
+        let mut world = World::new();
+        let entity = world.new_entity();
+        world.add_component_to_entity(entity, Health { current: 10, max: 10 });
+        let deaths = Arc::new(Mutex::new(0));
+        let mut schedule = Schedule::new();
+        schedule.add_system(Stage::PrePhysics, "check_health", check_health)
+            .reads::<Health>()
+            .reads::<Dead>();
+        let counter = deaths.clone();
+        schedule.add_system(Stage::Render, "count", move |world: &World| {
+            *counter.lock().unwrap() += world.read::<DeathEvent>().len();
+        });
+        let hurt = |world: &World, amount: i32| {
+            world.query::<(&mut Health,)>().get(entity).unwrap().0.damage(amount);
+        };
+
+        hurt(&world, 10);
+        schedule.run(&mut world);
+        assert_eq!(*deaths.lock().unwrap(), 1);
+        // hitting the body again doesn't kill it twice
+        for _ in 0..2 {
+            hurt(&world, 5);
+            schedule.run(&mut world);
+        }
+        assert_eq!(*deaths.lock().unwrap(), 1);
+
+        world.query::<(&mut Health,)>().get(entity).unwrap().0.heal(5);
+        schedule.run(&mut world);
+        hurt(&world, 5);
+        schedule.run(&mut world);
+        assert_eq!(*deaths.lock().unwrap(), 2);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::snapshot::ComponentRegistry;
+use crate::{EntityId, World};
+use serde::de::{self, DeserializeSeed, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A component kept in a prefab, copied onto every entity spawned from it
+pub trait PrefabComponent: Send + Sync {
+    fn insert_into(&self, world: &mut World, entity: EntityId);
+}
+
+impl<T: Clone + Send + Sync + 'static> PrefabComponent for T {
+    fn insert_into(&self, world: &mut World, entity: EntityId) {
+        world.add_component_to_entity(entity, self.clone());
+    }
+}
+
+/// Named set of components to spawn entities from.
+///
+/// Prefabs are written in RON, with components keyed by their registered name.
+/// Fields left out of a component take its default, and a prefab that `extends`
+/// another starts with the other's components and replaces the ones it lists.
+///
+/// ```ron
+/// (
+///     extends: "floor_tile",
+///     components: {
+///         "Sprite": (sprite: "tileset", sprite_state: (1, 0)),
+///     },
+/// )
+/// ```
+pub struct Prefab {
+    pub extends: Option<String>,
+    components: Vec<(String, Box<dyn PrefabComponent>)>,
+}
+
+/// Every loaded prefab, keyed by name
+pub struct Prefabs(pub HashMap<String, Prefab>);
+
+impl Prefabs {
+    /// Components for a prefab with everything it extends folded in, parents first.
+    /// Returns None if the prefab or one of its parents doesn't exist.
+    pub fn resolve(&self, name: &str) -> Option<Vec<&dyn PrefabComponent>> {
+        let mut chain = vec![self.0.get(name)?];
+        while let Some(parent) = chain.last().unwrap().extends.as_deref() {
+            if chain.len() > self.0.len() {
+                log::error!("prefab {} extends itself", name);
+                return None;
+            }
+            chain.push(self.0.get(parent)?);
+        }
+
+        let mut components: Vec<(&str, &dyn PrefabComponent)> = Vec::new();
+        for prefab in chain.iter().rev() {
+            for (component_name, component) in prefab.components.iter() {
+                match components.iter_mut().find(|(existing, _)| existing == component_name) {
+                    Some(existing) => existing.1 = component.as_ref(),
+                    None => components.push((component_name, component.as_ref())),
+                }
+            }
+        }
+        Some(components.into_iter().map(|(_, component)| component).collect())
+    }
+}
+
+/// Reads a prefab, looking component names up in the registry
+pub(crate) struct PrefabSeed<'r>(pub &'r ComponentRegistry);
+
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "lowercase")]
+enum Field {
+    Extends,
+    Components,
+}
+
+impl<'de, 'r> DeserializeSeed<'de> for PrefabSeed<'r> {
+    type Value = Prefab;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Prefab, D::Error> {
+        deserializer.deserialize_struct("Prefab", &["extends", "components"], self)
+    }
+}
+
+impl<'de, 'r> Visitor<'de> for PrefabSeed<'r> {
+    type Value = Prefab;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a prefab")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Prefab, A::Error> {
+        let mut prefab = Prefab { extends: None, components: Vec::new() };
+        while let Some(field) = map.next_key::<Field>()? {
+            match field {
+                Field::Extends => prefab.extends = Some(map.next_value()?),
+                Field::Components => prefab.components = map.next_value_seed(ComponentsSeed(self.0))?,
+            }
+        }
+        Ok(prefab)
+    }
+}
+
+struct ComponentsSeed<'r>(&'r ComponentRegistry);
+
+impl<'de, 'r> DeserializeSeed<'de> for ComponentsSeed<'r> {
+    type Value = Vec<(String, Box<dyn PrefabComponent>)>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'r> Visitor<'de> for ComponentsSeed<'r> {
+    type Value = Vec<(String, Box<dyn PrefabComponent>)>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("components keyed by their registered name")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut components = Vec::new();
+        while let Some(name) = map.next_key::<String>()? {
+            let seed = self.0
+                .prefab_seed(&name)
+                .ok_or_else(|| de::Error::custom(format!("component {} was never registered", name)))?;
+            components.push((name, map.next_value_seed(seed)?));
+        }
+        Ok(components)
+    }
+}
@@ -0,0 +1,488 @@
+use crate::{EntityId, World};
+use crate::SparseSet;
+use crate::schedule::change_ticks;
+use std::ops::{Deref, DerefMut};
+use std::sync::{RwLockReadGuard, RwLockWriteGuard};
+use std::marker::PhantomData;
+
+/// Something that can be fetched from a single entity, e.g. `&T`, `&mut T`,
+/// `Option<&T>` or a tuple of those
+pub trait QueryData {
+    /// Borrowed storage held for as long as the query lives
+    type State<'w>;
+    type Item<'q>;
+
+    /// Borrows the storage needed by this query.
+    /// Returns None if a required component has never been added to any entity.
+    fn borrow(world: &World) -> Option<Self::State<'_>>;
+
+    /// Entities that could possibly match, used to pick the smallest storage to
+    /// iterate. Returns None for terms that match anything, like `Option<&T>`.
+    fn candidates<'q, 'w>(state: &'q Self::State<'w>) -> Option<&'q [usize]>;
+
+    /// Fetches the data for the entity in slot `index`, or None if it doesn't match.
+    ///
+    /// # Safety
+    /// Callers must not hold two items fetched for the same index at once,
+    /// since `&mut T` items are handed out from a shared reference to the state.
+    unsafe fn fetch<'q, 'w>(state: &'q Self::State<'w>, index: usize) -> Option<Self::Item<'q>>;
+}
+
+/// Narrows a query down without fetching anything, e.g. `With<T>` or `Without<T>`
+pub trait QueryFilter {
+    type State<'w>;
+
+    fn borrow(world: &World) -> Self::State<'_>;
+    fn matches(state: &Self::State<'_>, index: usize) -> bool;
+}
+
+/// Only matches entities that have a `T`
+pub struct With<T>(PhantomData<T>);
+
+/// Only matches entities that don't have a `T`
+pub struct Without<T>(PhantomData<T>);
+
+/// Entities whose `T` was added since the current system last ran.
+///
+/// This reads the storage, so it can't be combined with `&mut T` in the same
+/// query. Use `Mut::is_added` there instead.
+pub struct Added<T>(PhantomData<T>);
+
+/// Entities whose `T` was added or mutated since the current system last ran.
+///
+/// This reads the storage, so it can't be combined with `&mut T` in the same
+/// query. Use `Mut::is_changed` there instead.
+pub struct Changed<T>(PhantomData<T>);
+
+/// Mutable access to one component storage. The pointers are taken once when the
+/// query is created and stay valid because the guard keeps the storage locked.
+pub struct WriteState<'w, T> {
+    guard: RwLockWriteGuard<'w, SparseSet<T>>,
+    dense: *mut T,
+    changed: *mut u64,
+    added: *const u64,
+    last_run: u64,
+    this_run: u64,
+}
+
+/// A `&mut T` query item that stamps the component as changed the first time
+/// it is written through, so fetching without writing doesn't count as a change
+pub struct Mut<'q, T> {
+    value: &'q mut T,
+    changed: &'q mut u64,
+    added: u64,
+    last_run: u64,
+    this_run: u64,
+}
+
+impl<'q, T> Mut<'q, T> {
+    /// True if the component was inserted since the current system last ran
+    pub fn is_added(&self) -> bool {
+        self.added > self.last_run
+    }
+
+    /// True if the component was inserted or written since the current system last ran
+    pub fn is_changed(&self) -> bool {
+        *self.changed > self.last_run
+    }
+
+    /// Gives up change tracking for a plain reference, marking the component as changed
+    pub fn into_inner(self) -> &'q mut T {
+        *self.changed = self.this_run;
+        self.value
+    }
+}
+
+impl<'q, T> Deref for Mut<'q, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<'q, T> DerefMut for Mut<'q, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        *self.changed = self.this_run;
+        self.value
+    }
+}
+
+impl<T: 'static> QueryData for &T {
+    type State<'w> = RwLockReadGuard<'w, SparseSet<T>>;
+    type Item<'q> = &'q T;
+
+    fn borrow(world: &World) -> Option<Self::State<'_>> {
+        world.borrow_component_vec::<T>()
+    }
+
+    fn candidates<'q, 'w>(state: &'q Self::State<'w>) -> Option<&'q [usize]> {
+        Some(state.entities())
+    }
+
+    unsafe fn fetch<'q, 'w>(state: &'q Self::State<'w>, index: usize) -> Option<Self::Item<'q>> {
+        state.get(index)
+    }
+}
+
+impl<T: 'static> QueryData for &mut T {
+    type State<'w> = WriteState<'w, T>;
+    type Item<'q> = Mut<'q, T>;
+
+    fn borrow(world: &World) -> Option<Self::State<'_>> {
+        let mut guard = world.borrow_component_vec_mut::<T>()?;
+        let dense = guard.dense_ptr();
+        let changed = guard.changed_ptr();
+        let added = guard.added_ptr();
+        let (last_run, this_run) = change_ticks(world);
+        Some(WriteState { guard, dense, changed, added, last_run, this_run })
+    }
+
+    fn candidates<'q, 'w>(state: &'q Self::State<'w>) -> Option<&'q [usize]> {
+        Some(state.guard.entities())
+    }
+
+    unsafe fn fetch<'q, 'w>(state: &'q Self::State<'w>, index: usize) -> Option<Self::Item<'q>> {
+        // Only the sparse index is read through the guard. The components and their
+        // ticks are reached through the pointers, since indexing the guard's vectors
+        // would borrow all of them and invalidate the items already handed out.
+        let position = state.guard.position(index)?;
+        Some(Mut {
+            value: &mut *state.dense.add(position),
+            changed: &mut *state.changed.add(position),
+            added: *state.added.add(position),
+            last_run: state.last_run,
+            this_run: state.this_run,
+        })
+    }
+}
+
+/// Optional components always match, yielding None when the entity lacks them
+impl<Q: QueryData> QueryData for Option<Q> {
+    type State<'w> = Option<Q::State<'w>>;
+    type Item<'q> = Option<Q::Item<'q>>;
+
+    fn borrow(world: &World) -> Option<Self::State<'_>> {
+        Some(Q::borrow(world))
+    }
+
+    fn candidates<'q, 'w>(_state: &'q Self::State<'w>) -> Option<&'q [usize]> {
+        None
+    }
+
+    unsafe fn fetch<'q, 'w>(state: &'q Self::State<'w>, index: usize) -> Option<Self::Item<'q>> {
+        match state {
+            Some(state) => Some(Q::fetch(state, index)),
+            None => Some(None),
+        }
+    }
+}
+
+macro_rules! impl_query_data_tuple {
+    ($($name:ident),*) => {
+        #[allow(non_snake_case)]
+        impl<$($name: QueryData),*> QueryData for ($($name,)*) {
+            type State<'w> = ($($name::State<'w>,)*);
+            type Item<'q> = ($($name::Item<'q>,)*);
+
+            fn borrow(world: &World) -> Option<Self::State<'_>> {
+                Some(($($name::borrow(world)?,)*))
+            }
+
+            fn candidates<'q, 'w>(state: &'q Self::State<'w>) -> Option<&'q [usize]> {
+                let ($($name,)*) = state;
+                let mut smallest: Option<&'q [usize]> = None;
+                $(
+                    if let Some(entities) = $name::candidates($name) {
+                        if smallest.map_or(true, |current| entities.len() < current.len()) {
+                            smallest = Some(entities);
+                        }
+                    }
+                )*
+                smallest
+            }
+
+            unsafe fn fetch<'q, 'w>(state: &'q Self::State<'w>, index: usize) -> Option<Self::Item<'q>> {
+                let ($($name,)*) = state;
+                Some(($($name::fetch($name, index)?,)*))
+            }
+        }
+    };
+}
+
+impl_query_data_tuple!(A);
+impl_query_data_tuple!(A, B);
+impl_query_data_tuple!(A, B, C);
+impl_query_data_tuple!(A, B, C, D);
+impl_query_data_tuple!(A, B, C, D, E);
+
+impl QueryFilter for () {
+    type State<'w> = ();
+
+    fn borrow(_world: &World) -> Self::State<'_> {}
+
+    fn matches(_state: &Self::State<'_>, _index: usize) -> bool {
+        true
+    }
+}
+
+impl<T: 'static> QueryFilter for With<T> {
+    type State<'w> = Option<RwLockReadGuard<'w, SparseSet<T>>>;
+
+    fn borrow(world: &World) -> Self::State<'_> {
+        world.borrow_component_vec::<T>()
+    }
+
+    fn matches(state: &Self::State<'_>, index: usize) -> bool {
+        match state {
+            Some(components) => components.contains(index),
+            None => false,
+        }
+    }
+}
+
+impl<T: 'static> QueryFilter for Without<T> {
+    type State<'w> = Option<RwLockReadGuard<'w, SparseSet<T>>>;
+
+    fn borrow(world: &World) -> Self::State<'_> {
+        world.borrow_component_vec::<T>()
+    }
+
+    fn matches(state: &Self::State<'_>, index: usize) -> bool {
+        !With::<T>::matches(state, index)
+    }
+}
+
+impl<T: 'static> QueryFilter for Added<T> {
+    type State<'w> = (Option<RwLockReadGuard<'w, SparseSet<T>>>, u64);
+
+    fn borrow(world: &World) -> Self::State<'_> {
+        let (last_run, _) = change_ticks(world);
+        (world.borrow_component_vec::<T>(), last_run)
+    }
+
+    fn matches(state: &Self::State<'_>, index: usize) -> bool {
+        let (components, last_run) = state;
+        match components.as_ref().and_then(|components| components.ticks(index)) {
+            Some((added, _)) => added > *last_run,
+            None => false,
+        }
+    }
+}
+
+impl<T: 'static> QueryFilter for Changed<T> {
+    type State<'w> = (Option<RwLockReadGuard<'w, SparseSet<T>>>, u64);
+
+    fn borrow(world: &World) -> Self::State<'_> {
+        let (last_run, _) = change_ticks(world);
+        (world.borrow_component_vec::<T>(), last_run)
+    }
+
+    fn matches(state: &Self::State<'_>, index: usize) -> bool {
+        let (components, last_run) = state;
+        match components.as_ref().and_then(|components| components.ticks(index)) {
+            Some((_, changed)) => changed > *last_run,
+            None => false,
+        }
+    }
+}
+
+macro_rules! impl_query_filter_tuple {
+    ($($name:ident),*) => {
+        #[allow(non_snake_case)]
+        impl<$($name: QueryFilter),*> QueryFilter for ($($name,)*) {
+            type State<'w> = ($($name::State<'w>,)*);
+
+            fn borrow(world: &World) -> Self::State<'_> {
+                ($($name::borrow(world),)*)
+            }
+
+            fn matches(state: &Self::State<'_>, index: usize) -> bool {
+                let ($($name,)*) = state;
+                $($name::matches($name, index))&&*
+            }
+        }
+    };
+}
+
+impl_query_filter_tuple!(A);
+impl_query_filter_tuple!(A, B);
+impl_query_filter_tuple!(A, B, C);
+
+/// Borrowed view over every entity matching `Q` and `F`.
+///
+/// The component storages stay borrowed until the query is dropped, so asking
+/// for the same component mutably in two live queries panics.
+pub struct Query<'w, Q: QueryData, F: QueryFilter = ()> {
+    world: &'w World,
+    data: Option<Q::State<'w>>,
+    filter: F::State<'w>,
+}
+
+impl<'w, Q: QueryData, F: QueryFilter> Query<'w, Q, F> {
+    pub fn new(world: &'w World) -> Self {
+        Self {
+            world,
+            data: Q::borrow(world),
+            filter: F::borrow(world),
+        }
+    }
+
+    /// Iterates matching entities as `(entity_id, components)`
+    pub fn iter(&mut self) -> QueryIter<'_, 'w, Q, F> {
+        let candidates = self.data.as_ref().and_then(|data| Q::candidates(data));
+        QueryIter { query: self, candidates, cursor: 0 }
+    }
+
+    /// Fetches the components of a single entity, if it is alive and matches
+    pub fn get(&mut self, entity: EntityId) -> Option<Q::Item<'_>> {
+        if !self.world.is_alive(entity) || !F::matches(&self.filter, entity.index) {
+            return None;
+        }
+        // SAFETY: `&mut self` guarantees no other item from this query is alive
+        unsafe { Q::fetch(self.data.as_ref()?, entity.index) }
+    }
+}
+
+impl<'q, 'w, Q: QueryData, F: QueryFilter> IntoIterator for &'q mut Query<'w, Q, F> {
+    type Item = (EntityId, Q::Item<'q>);
+    type IntoIter = QueryIter<'q, 'w, Q, F>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Walks the smallest required storage, or every entity slot if the query
+/// only has optional terms
+pub struct QueryIter<'q, 'w, Q: QueryData, F: QueryFilter> {
+    query: &'q Query<'w, Q, F>,
+    candidates: Option<&'q [usize]>,
+    cursor: usize,
+}
+
+impl<'q, 'w, Q: QueryData, F: QueryFilter> Iterator for QueryIter<'q, 'w, Q, F> {
+    type Item = (EntityId, Q::Item<'q>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let world = self.query.world;
+        let data = self.query.data.as_ref()?;
+        loop {
+            let index = match self.candidates {
+                Some(candidates) => *candidates.get(self.cursor)?,
+                None if self.cursor < world.entities_count => self.cursor,
+                None => return None,
+            };
+            self.cursor += 1;
+
+            if !world.alive[index] || !F::matches(&self.query.filter, index) {
+                continue;
+            }
+            // SAFETY: every entity is visited once, so items never alias
+            if let Some(item) = unsafe { Q::fetch(data, index) } {
+                return Some((EntityId { index, generation: world.generations[index] }, item));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::{Schedule, Stage};
+    use crate::{Collider, Coordinates};
+    use std::sync::{Arc, Mutex};
+
+    fn spawn_at(world: &mut World, x: f64) -> EntityId {
+        let entity = world.new_entity();
+        world.add_component_to_entity(entity, Coordinates { coord_x: x, coord_y: 0.0 });
+        entity
+    }
+
+    #[test]
+    fn matches_every_requested_component() {
+        let mut world = World::new();
+        let plain = spawn_at(&mut world, 1.0);
+        let solid = spawn_at(&mut world, 2.0);
+        world.add_component_to_entity(solid, Collider::default());
+
+        let mut both = world.query::<(&Coordinates, &Collider)>();
+        assert_eq!(both.iter().map(|(entity, _)| entity).collect::<Vec<_>>(), [solid]);
+        assert!(both.get(plain).is_none());
+
+        let mut optional = world.query::<(&Coordinates, Option<&Collider>)>();
+        let found: Vec<(f64, bool)> = optional.iter().map(|(_, (at, collider))| (at.coord_x, collider.is_some())).collect();
+        assert_eq!(found, [(1.0, false), (2.0, true)]);
+
+        let mut with = world.query_filtered::<(&Coordinates,), With<Collider>>();
+        assert_eq!(with.iter().map(|(entity, _)| entity).collect::<Vec<_>>(), [solid]);
+        let mut without = world.query_filtered::<(&Coordinates,), Without<Collider>>();
+        assert_eq!(without.iter().map(|(entity, _)| entity).collect::<Vec<_>>(), [plain]);
+    }
+
+    #[test]
+    fn writes_go_through_to_storage() {
+        let mut world = World::new();
+        let entity = spawn_at(&mut world, 1.0);
+        for (_, (mut at,)) in world.query::<(&mut Coordinates,)>().iter() {
+            at.coord_x += 1.0;
+        }
+        assert_eq!(world.query::<(&Coordinates,)>().get(entity).unwrap().0.coord_x, 2.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "already")]
+    fn conflicting_queries_panic() {
+        let mut world = World::new();
+        spawn_at(&mut world, 0.0);
+        let _reading = world.query::<(&Coordinates,)>();
+        let _writing = world.query::<(&mut Coordinates,)>();
+    }
+
+    #[test]
+    fn added_and_changed_since_last_run() {
+        let mut world = World::new();
+        let still = spawn_at(&mut world, 0.0);
+        let moving = spawn_at(&mut world, 0.0);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let mut schedule = Schedule::new();
+        schedule.add_system(Stage::Physics, "move", move |world: &World| {
+            for (entity, (mut at,)) in world.query::<(&mut Coordinates,)>().iter() {
+                // only writing through the item counts as a change
+                if entity == moving {
+                    at.coord_x += 1.0;
+                }
+            }
+        }).writes::<Coordinates>();
+        let watcher = seen.clone();
+        schedule.add_system(Stage::Render, "watch", move |world: &World| {
+            let added: Vec<EntityId> = world.query_filtered::<(&Coordinates,), Added<Coordinates>>().iter().map(|(entity, _)| entity).collect();
+            let changed: Vec<EntityId> = world.query_filtered::<(&Coordinates,), Changed<Coordinates>>().iter().map(|(entity, _)| entity).collect();
+            watcher.lock().unwrap().push((added, changed));
+        }).reads::<Coordinates>();
+
+        schedule.run(&mut world);
+        schedule.run(&mut world);
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen[0], (vec![still, moving], vec![still, moving]));
+        assert_eq!(seen[1], (vec![], vec![moving]));
+    }
+
+    #[test]
+    fn items_fetched_together_can_all_be_written() {
+        let mut world = World::new();
+        let entities: Vec<EntityId> = (0..3).map(|x| spawn_at(&mut world, x as f64)).collect();
+        let mut query = world.query::<(&mut Coordinates,)>();
+        let mut items: Vec<_> = query.iter().collect();
+        assert!(items.iter().all(|(_, (at,))| at.is_added() && at.is_changed()));
+        for (_, (at,)) in items.iter_mut() {
+            at.coord_y = at.coord_x * 2.0;
+        }
+        drop(items);
+        drop(query);
+        for (x, entity) in entities.into_iter().enumerate() {
+            assert_eq!(world.query::<(&Coordinates,)>().get(entity).unwrap().0.coord_y, x as f64 * 2.0);
+        }
+    }
+}
@@ -1,34 +1,49 @@
-//use gametesting::Camera;
-use gametesting::Coordinates;
-use gametesting::Sprite;
+//use crate::Camera;
+use crate::Coordinates;
+use crate::Sprite;
 
-use std::cell::RefMut;
-use std::cell::Ref;
 use std::collections::HashMap;
 use std::time::Instant;
-use std::borrow::BorrowMut;
-const GAME_HEIGHT: usize = 240;
-const GAME_WIDTH: usize = 426;
+pub const GAME_HEIGHT: usize = 240;
+pub const GAME_WIDTH: usize = 426;
 use crate::Image;
 use crate::Camera;
+use crate::Collider;
+use crate::EntityId;
+use crate::input::MousePosition;
+use crate::player::Grapple;
+use crate::query::Query;
+
+/// Every loaded sprite sheet, keyed by file name
+pub struct Images(pub HashMap<String, Image>);
+
+/// Sent when a sprite finishes the last frame of its row and starts over
+#[derive(Debug, Clone)]
+pub struct AnimationLoopEvent {
+    pub entity: EntityId,
+    pub row: u32,
+}
+
+/// RGBA pixels of the last rendered frame, copied to the window on redraw
+pub struct FrameBuffer(pub Vec<u8>);
 
 pub fn render_frame(
     last_updated: &Instant,
-    sprites: &mut RefMut<Vec<Option<Sprite>>>,
-    coordinates: & Ref<Vec<Option<Coordinates>>>,
+    sprites: &mut Query<(&Sprite, &Coordinates)>,
     images: &HashMap<String, Image>,
     frame: &mut [u8],
     camera: &Camera,
 ) {
         let mut pre_buffer: Vec<u8> = vec![80; GAME_WIDTH * GAME_HEIGHT * 4]; 
         
-        let zip = sprites.iter_mut().zip(coordinates.iter());
-        let mut both = zip.filter_map(|(health, name)| Some((health.as_mut()?, name.as_ref()?)));
-        
-        for (sprite, coordinates) in both.borrow_mut()
+        for (_, (sprite, coordinates)) in sprites.iter()
         {
             if sprite.visible {
-                let image = &images[sprite.sprite];
+                // Sprites whose sheet isn't loaded aren't drawn
+                let image = match images.get(&sprite.sprite) {
+                    Some(image) => image,
+                    None => continue,
+                };
                 
                 //Sets where the sprite exists relative to the camera
                 let mut x_rel = coordinates.coord_x as i32 - camera.x;
@@ -91,13 +106,6 @@ pub fn render_frame(
                         }
                     }
                 }
-                sprite.time_left -= 0.001;
-                if sprite.time_left <= 0.0 {
-                    sprite.sprite_state.0 += 1;
-                    sprite.sprite_state.0 %= image.row_length[sprite.sprite_state.1 as usize];
-                    sprite.time_left = image.row_time[sprite.sprite_state.1 as usize];
-                }
-                
             }
         }
 
@@ -114,6 +122,89 @@ pub fn render_frame(
         }
 }
 
+/// Advances every visible sprite's animation by `delta` seconds.
+/// Row times in sprite info files are counted in 60ths of a second.
+pub fn animate_frame(
+    delta: f64,
+    sprites: &mut Query<(&mut Sprite,)>,
+    images: &HashMap<String, Image>,
+    loops: &mut Vec<AnimationLoopEvent>,
+) {
+    for (entity, (mut sprite,)) in sprites.iter() {
+        if sprite.visible {
+            let image = match images.get(&sprite.sprite) {
+                Some(image) => image,
+                None => continue,
+            };
+            sprite.time_left -= delta * 60.0;
+            if sprite.time_left <= 0.0 {
+                sprite.sprite_state.0 += 1;
+                sprite.sprite_state.0 %= image.row_length[sprite.sprite_state.1 as usize];
+                sprite.time_left = image.row_time[sprite.sprite_state.1 as usize];
+                if sprite.sprite_state.0 == 0 {
+                    loops.push(AnimationLoopEvent { entity, row: sprite.sprite_state.1 });
+                }
+            }
+        }
+    }
+}
+
+/// Draws the cursor, then for every grapple the hook pointing at the cursor,
+/// or at the anchor and the rope leading to it once attached
+pub fn draw_grapples(
+    grapples: &mut Query<(&Grapple, &Coordinates, Option<&Collider>)>,
+    mouse: &MousePosition,
+    frame: &mut [u8],
+    camera: &Camera,
+) {
+    for (_, (grapple, coordinates, collider)) in grapples.iter() {
+        // Hooks come out of the middle of the collider
+        let (center_x, center_y) = match collider {
+            Some(collider) => (
+                coordinates.coord_x as i32 - camera.x + ((collider.boundary.0 + collider.boundary.2) / 2.0) as i32,
+                coordinates.coord_y as i32 - camera.y + ((collider.boundary.1 + collider.boundary.3) / 2.0) as i32,
+            ),
+            None => (coordinates.coord_x as i32 - camera.x, coordinates.coord_y as i32 - camera.y),
+        };
+        let (target_x, target_y) = match grapple.anchor {
+            Some((anchor_x, anchor_y)) => (anchor_x as i32 - camera.x, anchor_y as i32 - camera.y),
+            None => (mouse.x, mouse.y),
+        };
+
+        let angle = ((target_y - center_y) as f64).atan2((target_x - center_x) as f64);
+        let hook_x = center_x + (angle.cos() * 15.0) as i32;
+        let hook_y = center_y + (angle.sin() * 15.0) as i32;
+
+        if grapple.anchor.is_some() {
+            draw_line(frame, (hook_x, hook_y), (target_x, target_y), [0, 0, 255, 255]);
+            draw_pixel(frame, target_x, target_y, [255, 0, 0, 255]);
+        }
+        draw_pixel(frame, hook_x, hook_y, [0, 0, 255, 255]);
+    }
+
+    draw_pixel(frame, mouse.x, mouse.y, [0, 255, 0, 255]);
+}
+
+// Writes one pixel to the frame, with y counted up from the bottom of the screen.
+// Pixels off screen are skipped.
+fn draw_pixel(frame: &mut [u8], x: i32, y: i32, rgba: [u8; 4]) {
+    if x < 0 || y < 0 || x >= GAME_WIDTH as i32 || y >= GAME_HEIGHT as i32 {
+        return;
+    }
+    let index = ((GAME_HEIGHT - 1 - y as usize) * GAME_WIDTH + x as usize) * 4;
+    frame[index..index + 4].copy_from_slice(&rgba);
+}
+
+fn draw_line(frame: &mut [u8], start: (i32, i32), end: (i32, i32), rgba: [u8; 4]) {
+    let steps = (end.0 - start.0).abs().max((end.1 - start.1).abs());
+    for step in 0..=steps {
+        let t = if steps == 0 { 0.0 } else { step as f64 / steps as f64 };
+        let x = start.0 + ((end.0 - start.0) as f64 * t).round() as i32;
+        let y = start.1 + ((end.1 - start.1) as f64 * t).round() as i32;
+        draw_pixel(frame, x, y, rgba);
+    }
+}
+
 // Blends alpha between 2 pixels quickly. Not a correct implementation, as it ignores the background pixel's alpha.
 fn blend_alpha_fast(&src: &[u8; 4], &dst: &[u8; 4]) -> [u8; 4] {
     let mut blended = [255 as u8; 4];
@@ -0,0 +1,399 @@
+use crate::{Time, World};
+use std::any::{type_name, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Stages run in declaration order every tick
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Stage {
+    Input,
+    PrePhysics,
+    Physics,
+    Animation,
+    Render,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 5] = [
+        Stage::Input,
+        Stage::PrePhysics,
+        Stage::Physics,
+        Stage::Animation,
+        Stage::Render,
+    ];
+}
+
+/// Components and resources a system touches, keyed by `TypeId`
+#[derive(Default)]
+pub struct Access {
+    reads: Vec<(TypeId, &'static str)>,
+    writes: Vec<(TypeId, &'static str)>,
+}
+
+impl Access {
+    fn reads(&self, id: TypeId) -> bool {
+        self.reads.iter().chain(self.writes.iter()).any(|(read, _)| *read == id)
+    }
+
+    fn writes(&self, id: TypeId) -> bool {
+        self.writes.iter().any(|(written, _)| *written == id)
+    }
+
+    /// Two systems conflict if either writes something the other touches
+    fn conflicts_with(&self, other: &Access) -> Option<&'static str> {
+        let mine = self.writes.iter().find(|(id, _)| other.reads(*id));
+        let theirs = other.writes.iter().find(|(id, _)| self.reads(*id));
+        mine.or(theirs).map(|(_, name)| *name)
+    }
+}
+
+enum SystemFn {
+    /// Runs with shared access alongside other systems in its batch
+    Parallel(Box<ParallelFn>),
+    /// Runs alone with full access to the world
+    Exclusive(Box<dyn FnMut(&mut World)>),
+}
+
+pub struct System {
+    name: &'static str,
+    stage: Stage,
+    run: SystemFn,
+    access: Arc<Access>,
+    last_run: u64,
+    before: Vec<&'static str>,
+    after: Vec<&'static str>,
+}
+
+impl System {
+    /// Runs this system before the named system, which has to be in the same stage
+    pub fn before(&mut self, name: &'static str) -> &mut Self {
+        self.before.push(name);
+        self
+    }
+
+    /// Runs this system after the named system, which has to be in the same stage
+    pub fn after(&mut self, name: &'static str) -> &mut Self {
+        self.after.push(name);
+        self
+    }
+
+    /// Declares shared access to a component or resource
+    pub fn reads<T: 'static>(&mut self) -> &mut Self {
+        Arc::get_mut(&mut self.access).unwrap().reads.push((TypeId::of::<T>(), type_name::<T>()));
+        self
+    }
+
+    /// Declares mutable access to a component or resource
+    pub fn writes<T: 'static>(&mut self) -> &mut Self {
+        Arc::get_mut(&mut self.access).unwrap().writes.push((TypeId::of::<T>(), type_name::<T>()));
+        self
+    }
+
+    fn is_exclusive(&self) -> bool {
+        matches!(self.run, SystemFn::Exclusive(_))
+    }
+}
+
+/// What the current thread is running, used for access checks and change ticks
+#[derive(Clone)]
+struct Running {
+    name: &'static str,
+    /// None for exclusive systems, which can touch anything
+    access: Option<Arc<Access>>,
+    last_run: u64,
+    this_run: u64,
+}
+
+thread_local! {
+    static RUNNING: RefCell<Option<Running>> = const { RefCell::new(None) };
+}
+
+/// Panics if the parallel system running on this thread never declared access to `T`.
+/// Exclusive systems and code outside the schedule can borrow anything.
+pub fn check_access<T: 'static>(write: bool) {
+    RUNNING.with(|running| {
+        if let Some(Running { name, access: Some(access), .. }) = &*running.borrow() {
+            let id = TypeId::of::<T>();
+            if write && !access.writes(id) {
+                panic!("system {} writes {} without declaring it", name, type_name::<T>());
+            }
+            if !write && !access.reads(id) {
+                panic!("system {} reads {} without declaring it", name, type_name::<T>());
+            }
+        }
+    });
+}
+
+/// Change ticks as `(last_run, this_run)`. Anything stamped after `last_run` is
+/// new to the caller, and anything the caller changes gets stamped with `this_run`.
+///
+/// Code outside the schedule sees every change and gets a fresh tick each call.
+pub fn change_ticks(world: &World) -> (u64, u64) {
+    let running = RUNNING.with(|running| running.borrow().as_ref().map(|running| (running.last_run, running.this_run)));
+    running.unwrap_or_else(|| (0, world.increment_change_tick()))
+}
+
+type ParallelFn = dyn Fn(&World) + Send + Sync;
+
+fn with_running<R>(running: Running, run: impl FnOnce() -> R) -> R {
+    RUNNING.with(|current| *current.borrow_mut() = Some(running));
+    let result = run();
+    RUNNING.with(|current| *current.borrow_mut() = None);
+    result
+}
+
+fn run_batch(systems: &mut [System], batch: &[usize], world: &mut World) {
+    let mut jobs: Vec<(usize, Running)> = Vec::with_capacity(batch.len());
+    for &index in batch {
+        let system = &systems[index];
+        let access = match system.run {
+            SystemFn::Parallel(_) => Some(system.access.clone()),
+            SystemFn::Exclusive(_) => None,
+        };
+        let this_run = world.increment_change_tick();
+        jobs.push((index, Running { name: system.name, access, last_run: system.last_run, this_run }));
+    }
+
+    if let [(index, running)] = &jobs[..] {
+        let system = &mut systems[*index];
+        match &mut system.run {
+            SystemFn::Exclusive(run) => with_running(running.clone(), || run(world)),
+            SystemFn::Parallel(run) => with_running(running.clone(), || run(world)),
+        }
+    } else {
+        // Batches with more than one system only ever hold parallel systems.
+        // They run on rayon's global pool, so no threads are started per batch.
+        let world: &World = world;
+        let parallel: Vec<(&ParallelFn, Running)> = jobs
+            .iter()
+            .filter_map(|(index, running)| match &systems[*index].run {
+                SystemFn::Parallel(run) => Some((run.as_ref(), running.clone())),
+                SystemFn::Exclusive(_) => None,
+            })
+            .collect();
+        rayon::scope(|scope| {
+            for (run, running) in parallel {
+                scope.spawn(move |_| with_running(running, || run(world)));
+            }
+        });
+    }
+
+    for (index, running) in jobs {
+        systems[index].last_run = running.this_run;
+    }
+}
+
+/// Ordered list of systems, grouped into stages.
+///
+/// Within a stage, systems are split into batches. Systems in a batch have
+/// no ordering constraints between them and no conflicting access, so they
+/// run at the same time on rayon's thread pool.
+pub struct Schedule {
+    systems: Vec<System>,
+    batches: Option<Vec<Vec<usize>>>,
+    last_started: u64,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self {
+            systems: Vec::new(),
+            batches: None,
+            last_started: 0,
+        }
+    }
+
+    /// Registers a system under a unique name.
+    /// Use the returned handle to declare access and add `before`/`after` constraints.
+    pub fn add_system(
+        &mut self,
+        stage: Stage,
+        name: &'static str,
+        system: impl Fn(&World) + Send + Sync + 'static,
+    ) -> &mut System {
+        self.push(stage, name, SystemFn::Parallel(Box::new(system)))
+    }
+
+    /// Registers a system that needs `&mut World`. It always runs on its own.
+    pub fn add_exclusive_system(
+        &mut self,
+        stage: Stage,
+        name: &'static str,
+        system: impl FnMut(&mut World) + 'static,
+    ) -> &mut System {
+        self.push(stage, name, SystemFn::Exclusive(Box::new(system)))
+    }
+
+    fn push(&mut self, stage: Stage, name: &'static str, run: SystemFn) -> &mut System {
+        if self.systems.iter().any(|existing| existing.name == name) {
+            panic!("system {} registered twice", name);
+        }
+        self.batches = None;
+        self.systems.push(System {
+            name,
+            stage,
+            run,
+            access: Arc::new(Access::default()),
+            last_run: 0,
+            before: Vec::new(),
+            after: Vec::new(),
+        });
+        self.systems.last_mut().unwrap()
+    }
+
+    /// Runs the schedule once for every fixed step that real time has moved on by
+    /// since the last call, as counted by the `Time` resource. Returns the number of steps run.
+    pub fn run_fixed(&mut self, world: &mut World) -> u32 {
+        let steps = world.resource_mut::<Time>().unwrap().advance(Instant::now());
+        for _ in 0..steps {
+            self.run(world);
+        }
+        steps
+    }
+
+    /// Runs every stage once, applying queued commands at the end of each stage
+    pub fn run(&mut self, world: &mut World) {
+        if self.batches.is_none() {
+            self.batches = Some(self.build_batches());
+        }
+        let batches = self.batches.as_ref().unwrap();
+
+        // Every system has run once since the last tick started,
+        // so removals and events from before then have been seen by everyone
+        world.clear_removed_before(self.last_started);
+        world.clear_events_before(self.last_started);
+        self.last_started = world.increment_change_tick();
+
+        for (number, batch) in batches.iter().enumerate() {
+            run_batch(&mut self.systems, batch, world);
+
+            let stage = self.systems[batch[0]].stage;
+            let stage_ends = batches.get(number + 1).is_none_or(|next| self.systems[next[0]].stage != stage);
+            if stage_ends {
+                world.apply_commands();
+            }
+        }
+    }
+
+    /// Sorts systems by stage, then topologically by their before/after constraints,
+    /// then groups neighbouring systems that can safely run together.
+    /// Systems without constraints keep the order they were added in.
+    fn build_batches(&self) -> Vec<Vec<usize>> {
+        let mut batches: Vec<Vec<usize>> = Vec::new();
+
+        for stage in Stage::ALL {
+            let members: Vec<usize> = (0..self.systems.len())
+                .filter(|&index| self.systems[index].stage == stage)
+                .collect();
+            let position: HashMap<&str, usize> = members
+                .iter()
+                .map(|&index| (self.systems[index].name, index))
+                .collect();
+
+            // edges[a] holds every system that has to wait for a
+            let mut edges: HashMap<usize, Vec<usize>> = HashMap::new();
+            let mut waiting_on: HashMap<usize, usize> = members.iter().map(|&index| (index, 0)).collect();
+            for &index in members.iter() {
+                let system = &self.systems[index];
+                // a typo would otherwise leave the system unordered without a word
+                let find = |name: &&'static str| match position.get(name) {
+                    Some(&other) => other,
+                    None => panic!("system {} is ordered against {}, which isn't a system in the {:?} stage", system.name, name, stage),
+                };
+                let befores = system.before.iter().map(find).map(|other| (index, other));
+                let afters = system.after.iter().map(find).map(|other| (other, index));
+                for (first, second) in befores.chain(afters) {
+                    edges.entry(first).or_default().push(second);
+                    *waiting_on.get_mut(&second).unwrap() += 1;
+                }
+            }
+
+            let mut order = Vec::with_capacity(members.len());
+            let mut remaining = members.clone();
+            while !remaining.is_empty() {
+                let next = remaining
+                    .iter()
+                    .position(|index| waiting_on[index] == 0)
+                    .unwrap_or_else(|| {
+                        let names: Vec<&str> = remaining.iter().map(|&index| self.systems[index].name).collect();
+                        panic!("system ordering cycle in {:?} stage between {:?}", stage, names)
+                    });
+                let index = remaining.remove(next);
+                for other in edges.get(&index).into_iter().flatten() {
+                    *waiting_on.get_mut(other).unwrap() -= 1;
+                }
+                order.push(index);
+            }
+
+            // Batches never span two stages
+            let mut batch: Vec<usize> = Vec::new();
+            for index in order {
+                let system = &self.systems[index];
+                let fits = !system.is_exclusive()
+                    && batch.iter().all(|&other| {
+                        let other_system = &self.systems[other];
+                        if other_system.is_exclusive() {
+                            return false;
+                        }
+                        if edges.get(&other).is_some_and(|after| after.contains(&index)) {
+                            return false;
+                        }
+                        if let Some(shared) = system.access.conflicts_with(&other_system.access) {
+                            log::debug!("{} and {} both use {}, running them in order", system.name, other_system.name, shared);
+                            return false;
+                        }
+                        true
+                    });
+                if !fits && !batch.is_empty() {
+                    batches.push(std::mem::take(&mut batch));
+                }
+                batch.push(index);
+            }
+            if !batch.is_empty() {
+                batches.push(batch);
+            }
+        }
+        batches
+    }
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_by_before_and_after() {
+        let mut schedule = Schedule::new();
+        schedule.add_system(Stage::PrePhysics, "last", |_: &World| {}).after("middle");
+        schedule.add_system(Stage::PrePhysics, "middle", |_: &World| {});
+        schedule.add_system(Stage::PrePhysics, "first", |_: &World| {}).before("middle");
+        let names: Vec<&str> = schedule.build_batches().concat().iter().map(|&index| schedule.systems[index].name).collect();
+        assert_eq!(names, ["first", "middle", "last"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "camra")]
+    fn unknown_system_in_ordering_panics() {
+        let mut schedule = Schedule::new();
+        schedule.add_system(Stage::PrePhysics, "camera", |_: &World| {});
+        schedule.add_system(Stage::PrePhysics, "follow", |_: &World| {}).after("camra");
+        schedule.build_batches();
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't a system in the Render stage")]
+    fn ordering_across_stages_panics() {
+        let mut schedule = Schedule::new();
+        schedule.add_system(Stage::Physics, "simulate", |_: &World| {});
+        schedule.add_system(Stage::Render, "draw", |_: &World| {}).after("simulate");
+        schedule.build_batches();
+    }
+}
@@ -0,0 +1,380 @@
+use crate::prefab::PrefabComponent;
+use crate::physics::TriggerContacts;
+use crate::spatial::SpatialHash;
+use crate::{EntityId, SparseSet, World};
+use serde::de::{self, DeserializeOwned, DeserializeSeed, MapAccess, SeqAccess, Visitor};
+use serde::ser::{SerializeMap, SerializeStruct};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::any::TypeId;
+use std::fmt;
+use std::sync::RwLockReadGuard;
+
+type SaveFn = for<'w> fn(&'w World) -> Option<Box<dyn erased_serde::Serialize + 'w>>;
+type LoadFn = for<'de> fn(&mut dyn erased_serde::Deserializer<'de>) -> Result<Box<dyn LoadedComponents>, erased_serde::Error>;
+type LoadOneFn = for<'de> fn(&mut dyn erased_serde::Deserializer<'de>) -> Result<Box<dyn PrefabComponent>, erased_serde::Error>;
+
+struct Registration {
+    name: &'static str,
+    type_id: TypeId,
+    save: SaveFn,
+    load: LoadFn,
+    load_one: LoadOneFn,
+}
+
+/// Component types that are saved in snapshots and can be used in prefabs,
+/// keyed by the name they are written under.
+///
+/// Names are what ends up in the saved data, so renaming a Rust type is fine
+/// but renaming its registration breaks older saves.
+pub struct ComponentRegistry {
+    registrations: Vec<Registration>,
+}
+
+impl ComponentRegistry {
+    pub fn new() -> Self {
+        Self { registrations: Vec::new() }
+    }
+
+    pub fn register<T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static>(&mut self, name: &'static str) {
+        if let Some(existing) = self.registrations.iter().find(|registration| registration.name == name) {
+            if existing.type_id != TypeId::of::<T>() {
+                panic!("component name {} registered twice", name);
+            }
+            return;
+        }
+        self.registrations.push(Registration {
+            name,
+            type_id: TypeId::of::<T>(),
+            save: save_components::<T>,
+            load: load_components::<T>,
+            load_one: load_component::<T>,
+        });
+    }
+
+    fn get(&self, name: &str) -> Option<&Registration> {
+        self.registrations.iter().find(|registration| registration.name == name)
+    }
+
+    /// Reads a single component of the type registered under `name`
+    pub(crate) fn prefab_seed(&self, name: &str) -> Option<PrefabComponentSeed<'_>> {
+        self.get(name).map(PrefabComponentSeed)
+    }
+}
+
+impl Default for ComponentRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Every component of one type, as `(entity index, component)` in storage order
+struct StoredComponents<'w, T>(RwLockReadGuard<'w, SparseSet<T>>);
+
+impl<'w, T: Serialize> Serialize for StoredComponents<'w, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let components = &self.0;
+        serializer.collect_seq(components.entities().iter().map(|&entity| (entity, components.get(entity).unwrap())))
+    }
+}
+
+fn save_components<T: Serialize + 'static>(world: &World) -> Option<Box<dyn erased_serde::Serialize + '_>> {
+    let components = world.borrow_component_vec::<T>()?;
+    Some(Box::new(StoredComponents(components)))
+}
+
+/// Components read back from a snapshot, waiting to be put into the world
+trait LoadedComponents {
+    fn insert_into(self: Box<Self>, world: &mut World);
+}
+
+impl<T: Send + Sync + 'static> LoadedComponents for Vec<(usize, T)> {
+    fn insert_into(self: Box<Self>, world: &mut World) {
+        for (index, component) in *self {
+            let generation = world.generations.get(index).copied().unwrap_or(0);
+            world.add_component_to_entity(EntityId { index, generation }, component);
+        }
+    }
+}
+
+fn load_components<T: DeserializeOwned + Send + Sync + 'static>(
+    deserializer: &mut dyn erased_serde::Deserializer<'_>,
+) -> Result<Box<dyn LoadedComponents>, erased_serde::Error> {
+    let components: Vec<(usize, T)> = erased_serde::deserialize(deserializer)?;
+    Ok(Box::new(components))
+}
+
+fn load_component<T: DeserializeOwned + Clone + Send + Sync + 'static>(
+    deserializer: &mut dyn erased_serde::Deserializer<'_>,
+) -> Result<Box<dyn PrefabComponent>, erased_serde::Error> {
+    let component: T = erased_serde::deserialize(deserializer)?;
+    Ok(Box::new(component))
+}
+
+/// Entity slots, saved as they are so handles kept in components stay valid
+#[derive(Serialize, Deserialize)]
+struct Entities {
+    generations: Vec<u32>,
+    alive: Vec<bool>,
+    free: Vec<usize>,
+}
+
+/// Serializes the entity slots and every registered component in the world.
+/// Components of unregistered types are left out.
+pub(crate) struct SnapshotRef<'w>(pub &'w World);
+
+impl<'w> Serialize for SnapshotRef<'w> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let world = self.0;
+        let entities = Entities {
+            generations: world.generations.clone(),
+            alive: world.alive.clone(),
+            free: world.free_entities.clone(),
+        };
+        let mut snapshot = serializer.serialize_struct("Snapshot", 2)?;
+        snapshot.serialize_field("entities", &entities)?;
+        snapshot.serialize_field("components", &ComponentsRef(world))?;
+        snapshot.end()
+    }
+}
+
+struct ComponentsRef<'w>(&'w World);
+
+impl<'w> Serialize for ComponentsRef<'w> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let stored: Vec<_> = self.0.registry.registrations
+            .iter()
+            .filter_map(|registration| Some((registration.name, (registration.save)(self.0)?)))
+            .collect();
+        let mut map = serializer.serialize_map(Some(stored.len()))?;
+        for (name, components) in stored.iter() {
+            map.serialize_entry(name, components)?;
+        }
+        map.end()
+    }
+}
+
+/// A snapshot read back in full, before any of it touches the world
+pub(crate) struct Snapshot {
+    entities: Entities,
+    components: Vec<Box<dyn LoadedComponents>>,
+}
+
+impl Snapshot {
+    /// Replaces every entity and component in the world with the snapshot's
+    pub(crate) fn restore(self, world: &mut World) {
+        world.component_vecs.clear();
+        world.removed.clear();
+        world.entities_count = self.entities.generations.len();
+        world.generations = self.entities.generations;
+        world.alive = self.entities.alive;
+        world.free_entities = self.entities.free;
+        for components in self.components {
+            components.insert_into(world);
+        }
+        if let Some(mut spatial) = world.resource_mut::<SpatialHash>() {
+            spatial.clear();
+        }
+        // the old entities' trigger overlaps would otherwise pair up with whatever reuses their ids
+        if let Some(mut contacts) = world.resource_mut::<TriggerContacts>() {
+            contacts.0.clear();
+        }
+    }
+}
+
+/// Reads a snapshot, looking component names up in the registry
+pub(crate) struct SnapshotSeed<'r>(pub &'r ComponentRegistry);
+
+const FIELDS: &[&str] = &["entities", "components"];
+
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "lowercase")]
+enum Field {
+    Entities,
+    Components,
+}
+
+impl<'de, 'r> DeserializeSeed<'de> for SnapshotSeed<'r> {
+    type Value = Snapshot;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Snapshot, D::Error> {
+        deserializer.deserialize_struct("Snapshot", FIELDS, self)
+    }
+}
+
+fn check_entities<E: de::Error>(entities: &Entities) -> Result<(), E> {
+    let count = entities.generations.len();
+    if entities.alive.len() != count {
+        return Err(E::custom("snapshot entity slots don't line up"));
+    }
+    // new_entity hands out free slots as they are, so a live or repeated one would go to two entities
+    let mut listed = vec![false; count];
+    for &index in entities.free.iter() {
+        if index >= count || entities.alive[index] || std::mem::replace(&mut listed[index], true) {
+            return Err(E::custom(format!("snapshot free list can't hand out slot {}", index)));
+        }
+    }
+    Ok(())
+}
+
+impl<'de, 'r> Visitor<'de> for SnapshotSeed<'r> {
+    type Value = Snapshot;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a world snapshot")
+    }
+
+    // Binary formats hand struct fields over in order
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Snapshot, A::Error> {
+        let entities: Entities = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let components = seq.next_element_seed(ComponentsSeed(self.0))?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        check_entities(&entities)?;
+        Ok(Snapshot { entities, components })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Snapshot, A::Error> {
+        let mut entities: Option<Entities> = None;
+        let mut components = None;
+        while let Some(field) = map.next_key::<Field>()? {
+            match field {
+                Field::Entities => entities = Some(map.next_value()?),
+                Field::Components => components = Some(map.next_value_seed(ComponentsSeed(self.0))?),
+            }
+        }
+        let entities = entities.ok_or_else(|| de::Error::missing_field("entities"))?;
+        let components = components.ok_or_else(|| de::Error::missing_field("components"))?;
+        check_entities(&entities)?;
+        Ok(Snapshot { entities, components })
+    }
+}
+
+struct ComponentsSeed<'r>(&'r ComponentRegistry);
+
+impl<'de, 'r> DeserializeSeed<'de> for ComponentsSeed<'r> {
+    type Value = Vec<Box<dyn LoadedComponents>>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'r> Visitor<'de> for ComponentsSeed<'r> {
+    type Value = Vec<Box<dyn LoadedComponents>>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("components keyed by their registered name")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut loaded = Vec::new();
+        while let Some(name) = map.next_key::<String>()? {
+            let registration = self.0
+                .get(&name)
+                .ok_or_else(|| de::Error::custom(format!("component {} was never registered", name)))?;
+            loaded.push(map.next_value_seed(RegistrationSeed(registration))?);
+        }
+        Ok(loaded)
+    }
+}
+
+struct RegistrationSeed<'r>(&'r Registration);
+
+impl<'de, 'r> DeserializeSeed<'de> for RegistrationSeed<'r> {
+    type Value = Box<dyn LoadedComponents>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        let mut deserializer = <dyn erased_serde::Deserializer>::erase(deserializer);
+        (self.0.load)(&mut deserializer).map_err(de::Error::custom)
+    }
+}
+
+pub(crate) struct PrefabComponentSeed<'r>(&'r Registration);
+
+impl<'de, 'r> DeserializeSeed<'de> for PrefabComponentSeed<'r> {
+    type Value = Box<dyn PrefabComponent>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        let mut deserializer = <dyn erased_serde::Deserializer>::erase(deserializer);
+        (self.0.load_one)(&mut deserializer).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hierarchy::Parent;
+    use crate::physics::TriggerContacts;
+    use crate::{Coordinates, EntityId, World};
+
+    fn contents(world: &World) -> Vec<(EntityId, f64, Option<EntityId>)> {
+        world
+            .query::<(&Coordinates, Option<&Parent>)>()
+            .iter()
+            .map(|(entity, (at, parent))| (entity, at.coord_x, parent.map(|parent| parent.0)))
+            .collect()
+    }
+
+    fn filled_world() -> World {
+        let mut world = World::new();
+        let parent = world.new_entity();
+        let despawned = world.new_entity();
+        let child = world.new_entity();
+        world.add_component_to_entity(parent, Coordinates { coord_x: 1.0, coord_y: 0.0 });
+        world.add_component_to_entity(child, Coordinates { coord_x: 2.0, coord_y: 0.0 });
+        world.set_parent(child, parent);
+        world.despawn(despawned);
+        world
+    }
+
+    #[test]
+    fn bincode_round_trip() {
+        let world = filled_world();
+        let saved = world.save_snapshot().unwrap();
+        let mut loaded = World::new();
+        loaded.new_entity();
+        loaded.load_snapshot(&saved).unwrap();
+        assert_eq!(contents(&loaded), contents(&world));
+        // the free slot comes back with its bumped generation
+        assert_eq!(loaded.new_entity(), EntityId { index: 1, generation: 1 });
+    }
+
+    #[test]
+    fn ron_round_trip() {
+        let world = filled_world();
+        let saved = world.save_snapshot_ron().unwrap();
+        let mut loaded = World::new();
+        loaded.load_snapshot_ron(&saved).unwrap();
+        assert_eq!(contents(&loaded), contents(&world));
+    }
+
+    #[test]
+    fn restoring_forgets_trigger_contacts() {
+        let world = filled_world();
+        let saved = world.save_snapshot().unwrap();
+        let mut loaded = World::new();
+        let (a, b) = (loaded.new_entity(), loaded.new_entity());
+        loaded.resource_mut::<TriggerContacts>().unwrap().0.insert((a, b));
+        loaded.load_snapshot(&saved).unwrap();
+        assert!(loaded.resource::<TriggerContacts>().unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn bad_snapshots_leave_the_world_alone() {
+        let mut world = filled_world();
+        let before = contents(&world);
+        assert!(world.load_snapshot(&[1, 2, 3]).is_err());
+        assert!(world.load_snapshot_ron("(entities: (generations: [0], alive: [], free: []), components: {})").is_err());
+        assert_eq!(contents(&world), before);
+    }
+
+    #[test]
+    fn free_slots_have_to_be_dead_and_listed_once() {
+        let mut world = World::new();
+        let snapshot = |free: &str| format!("(entities: (generations: [0, 0], alive: [false, true], free: [{}]), components: {{}})", free);
+        assert!(world.load_snapshot_ron(&snapshot("0, 0")).is_err());
+        assert!(world.load_snapshot_ron(&snapshot("1")).is_err());
+        assert!(world.load_snapshot_ron(&snapshot("2")).is_err());
+
+        world.load_snapshot_ron(&snapshot("0")).unwrap();
+        assert_eq!(world.new_entity(), EntityId { index: 0, generation: 0 });
+        assert_eq!(world.new_entity(), EntityId { index: 2, generation: 0 });
+    }
+}
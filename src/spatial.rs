@@ -0,0 +1,120 @@
+use crate::EntityId;
+use std::collections::{HashMap, HashSet};
+
+/// Side length of a spatial hash cell, about four tiles
+pub const CELL_SIZE: f64 = 64.0;
+
+/// Axis aligned box as x, y, width, height in world space
+pub type Aabb = (f64, f64, f64, f64);
+
+type CellRange = ((i32, i32), (i32, i32));
+
+/// World-space broadphase covering levels of any size.
+///
+/// Colliders are bucketed into square cells by the area they cover, and only
+/// move between buckets when they cross into a different set of cells.
+/// Physics keeps it up to date every tick, so gameplay can ask which colliders
+/// are in an area with `query_region` instead of looping over every entity.
+pub struct SpatialHash {
+    cell_size: f64,
+    cells: HashMap<(i32, i32), Vec<EntityId>>,
+    entries: HashMap<EntityId, (Aabb, CellRange)>,
+}
+
+impl SpatialHash {
+    pub fn new(cell_size: f64) -> Self {
+        Self {
+            cell_size,
+            cells: HashMap::new(),
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Inserts the entity or moves it to its new bounds
+    pub fn update(&mut self, entity: EntityId, bounds: Aabb) {
+        let range = self.cell_range(bounds);
+        match self.entries.get_mut(&entity) {
+            Some((old_bounds, old_range)) => {
+                *old_bounds = bounds;
+                if *old_range == range {
+                    return;
+                }
+                let old_range = std::mem::replace(old_range, range);
+                self.remove_from_cells(entity, old_range);
+            },
+            None => {
+                self.entries.insert(entity, (bounds, range));
+            },
+        }
+        for cell in cells_in(range) {
+            self.cells.entry(cell).or_default().push(entity);
+        }
+    }
+
+    /// Forgets the entity. Returns false if it wasn't in the hash.
+    pub fn remove(&mut self, entity: EntityId) -> bool {
+        match self.entries.remove(&entity) {
+            Some((_, range)) => {
+                self.remove_from_cells(entity, range);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Forgets every entity
+    pub fn clear(&mut self) {
+        self.cells.clear();
+        self.entries.clear();
+    }
+
+    /// Bounds the entity was last updated with
+    pub fn bounds(&self, entity: EntityId) -> Option<Aabb> {
+        self.entries.get(&entity).map(|(bounds, _)| *bounds)
+    }
+
+    /// Every entity whose bounds overlap the region, each listed once
+    pub fn query_region(&self, region: Aabb) -> Vec<EntityId> {
+        let mut seen = HashSet::new();
+        let mut found = Vec::new();
+        for cell in cells_in(self.cell_range(region)) {
+            for &entity in self.cells.get(&cell).into_iter().flatten() {
+                if seen.insert(entity) && overlaps(self.entries[&entity].0, region) {
+                    found.push(entity);
+                }
+            }
+        }
+        found
+    }
+
+    fn cell_range(&self, (x, y, width, height): Aabb) -> CellRange {
+        let cell = |value: f64| (value / self.cell_size).floor() as i32;
+        ((cell(x), cell(y)), (cell(x + width), cell(y + height)))
+    }
+
+    fn remove_from_cells(&mut self, entity: EntityId, range: CellRange) {
+        for cell in cells_in(range) {
+            if let Some(bucket) = self.cells.get_mut(&cell) {
+                bucket.retain(|&other| other != entity);
+                if bucket.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+    }
+}
+
+impl Default for SpatialHash {
+    fn default() -> Self {
+        Self::new(CELL_SIZE)
+    }
+}
+
+fn cells_in(((start_x, start_y), (end_x, end_y)): CellRange) -> impl Iterator<Item = (i32, i32)> {
+    (start_x..=end_x).flat_map(move |x| (start_y..=end_y).map(move |y| (x, y)))
+}
+
+// touching edges don't count, same as the narrowphase
+pub(crate) fn overlaps(a: Aabb, b: Aabb) -> bool {
+    a.0 < b.0 + b.2 && a.0 + a.2 > b.0 && a.1 < b.1 + b.3 && a.1 + a.3 > b.1
+}
@@ -0,0 +1,20 @@
+use serde::{Deserialize, Serialize};
+
+/// Unique-ish name to look an entity up by with `World::find_by_name`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Name(pub String);
+
+impl Name {
+    pub fn new(name: &str) -> Self {
+        Self(name.to_string())
+    }
+}
+
+/// Marks entities controlled by input and followed by the camera.
+/// Filter on it with `With<Player>` rather than relying on spawn order.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct Player;
+
+/// Marks hostile entities
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct Enemy;
@@ -0,0 +1,808 @@
+use crate::commands::Commands;
+use crate::events::{EventQueue, Events};
+use crate::hierarchy::{self, Children, LocalOffset, Parent};
+use crate::input::{self, ActionEvent, FrameInput, InputMap, MousePosition};
+use crate::physics::{self, CollisionEvent, CollisionLayers, TriggerContacts, TriggerEvent};
+use crate::player::{self, Dead, DeathEvent, Grapple, Health};
+use crate::prefab::{PrefabSeed, Prefabs};
+use crate::query::{Query, QueryData, QueryFilter, With};
+use crate::render::{self, AnimationLoopEvent, FrameBuffer, Images};
+use crate::schedule::{self, Schedule, Stage};
+use crate::snapshot::{ComponentRegistry, SnapshotRef, SnapshotSeed};
+use crate::spatial::SpatialHash;
+use crate::tags::{Enemy, Name, Player};
+use crate::worldinit::load_images;
+use crate::{Camera, Collider, ComponentVec, Coordinates, SparseSet, Sprite};
+use bincode::Options;
+use log::error;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::any::{type_name, Any, TypeId};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Instant;
+
+/// Handle to an entity slot in the world
+///
+/// The generation is bumped every time the slot is despawned, so a handle kept
+/// around after its entity is gone no longer matches whatever reuses the slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct EntityId {
+    pub index: usize,
+    pub generation: u32,
+}
+
+// Storage is never waited on. Systems that would block each other are put in
+// separate batches by the schedule, so a held lock here means the same thread
+// is borrowing twice, which would otherwise deadlock.
+fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    match lock.try_read() {
+        Ok(guard) => guard,
+        Err(_) => panic!("{} is already mutably borrowed", type_name::<T>()),
+    }
+}
+
+fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    match lock.try_write() {
+        Ok(guard) => guard,
+        Err(_) => panic!("{} is already borrowed", type_name::<T>()),
+    }
+}
+
+/// Fixed steps per second unless the game changes `Time::tick_rate`
+pub const DEFAULT_TICK_RATE: f64 = 60.0;
+/// Fixed steps run for one frame at most unless the game changes `Time::max_substeps`
+pub const DEFAULT_MAX_SUBSTEPS: u32 = 5;
+
+/// Time bookkeeping for the fixed timestep.
+///
+/// Every run of the schedule is one fixed step of `delta()` seconds, so physics and
+/// animation play at the same speed however often the window sends events.
+/// `Schedule::run_fixed` works out how many steps are due from real elapsed time.
+pub struct Time {
+    /// When the elapsed time was last added to the accumulator
+    pub last_updated: Instant,
+    /// Fixed steps per second
+    pub tick_rate: f64,
+    /// Most steps run to catch up in one go. Time beyond that is dropped so a slow
+    /// frame can't snowball into ever more steps.
+    pub max_substeps: u32,
+    accumulator: f64,
+}
+
+impl Time {
+    pub fn new(tick_rate: f64) -> Self {
+        Self {
+            last_updated: Instant::now(),
+            tick_rate,
+            max_substeps: DEFAULT_MAX_SUBSTEPS,
+            accumulator: 0.0,
+        }
+    }
+
+    /// Length of one fixed step in seconds
+    pub fn delta(&self) -> f64 {
+        1.0 / self.tick_rate
+    }
+
+    /// Adds the real time passed since the last call and returns how many fixed steps are due
+    pub fn advance(&mut self, now: Instant) -> u32 {
+        self.accumulator += now.saturating_duration_since(self.last_updated).as_secs_f64();
+        self.last_updated = now;
+
+        let delta = self.delta();
+        let mut steps = 0;
+        while self.accumulator >= delta {
+            if steps == self.max_substeps {
+                self.accumulator = 0.0;
+                break;
+            }
+            self.accumulator -= delta;
+            steps += 1;
+        }
+        steps
+    }
+}
+
+impl Default for Time {
+    fn default() -> Self {
+        Self::new(DEFAULT_TICK_RATE)
+    }
+}
+
+pub struct World {
+    pub(crate) entities_count: usize,
+    pub(crate) generations: Vec<u32>,
+    pub(crate) alive: Vec<bool>,
+    pub(crate) free_entities: Vec<usize>,
+    pub(crate) component_vecs: HashMap<TypeId, Box<dyn ComponentVec>>,
+    resources: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+    commands: Commands,
+    change_tick: AtomicU64,
+    pub(crate) removed: HashMap<TypeId, Vec<(EntityId, u64)>>,
+    events: HashMap<TypeId, Box<dyn EventQueue>>,
+    pub(crate) registry: ComponentRegistry,
+    names: Mutex<NameIndex>,
+}
+
+/// Entities by name, along with the version of the `Name` storage it was built from
+#[derive(Default)]
+struct NameIndex {
+    version: u64,
+    entities: HashMap<String, EntityId>,
+}
+
+impl World {
+    /// Creates an empty world with the engine's resources, events and components set up.
+    /// Nothing is read from disk, so `Images` starts out empty.
+    pub fn new() -> Self {
+        let mut world = Self {
+            entities_count: 0,
+            generations: Vec::new(),
+            alive: Vec::new(),
+            free_entities: Vec::new(),
+            component_vecs: HashMap::new(),
+            resources: HashMap::new(),
+            commands: Commands::new(),
+            change_tick: AtomicU64::new(0),
+            removed: HashMap::new(),
+            events: HashMap::new(),
+            registry: ComponentRegistry::new(),
+            names: Mutex::new(NameIndex::default()),
+        };
+        world.insert_resource(Camera { x: 0, y: 0 });
+        world.insert_resource(MousePosition { x: 160, y: 90 });
+        world.insert_resource(Time::default());
+        world.insert_resource(Images(HashMap::new()));
+        world.insert_resource(InputMap::new());
+        world.insert_resource(FrameInput::new());
+        world.insert_resource(FrameBuffer(vec![0; render::GAME_WIDTH * render::GAME_HEIGHT * 4]));
+        world.insert_resource(Prefabs(HashMap::new()));
+        world.insert_resource(SpatialHash::default());
+        world.insert_resource(TriggerContacts::default());
+        world.insert_resource(CollisionLayers::new());
+        world.add_event::<CollisionEvent>();
+        world.add_event::<TriggerEvent>();
+        world.add_event::<ActionEvent>();
+        world.add_event::<AnimationLoopEvent>();
+        world.add_event::<DeathEvent>();
+        world.register_component::<Coordinates>("Coordinates");
+        world.register_component::<Collider>("Collider");
+        world.register_component::<Sprite>("Sprite");
+        world.register_component::<Parent>("Parent");
+        world.register_component::<Children>("Children");
+        world.register_component::<LocalOffset>("LocalOffset");
+        world.register_component::<Health>("Health");
+        world.register_component::<Dead>("Dead");
+        world.register_component::<Grapple>("Grapple");
+        world.register_component::<Name>("Name");
+        world.register_component::<Player>("Player");
+        world.register_component::<Enemy>("Enemy");
+        world
+    }
+    
+    /// Same as `new`, with every sprite sheet in the `sprites/` folder loaded into `Images`
+    pub fn with_default_assets() -> Self {
+        let mut world = Self::new();
+        world.insert_resource(Images(load_images()));
+        world
+    }
+    
+    /// Creates an entity with no components, reusing a despawned slot if one is free
+    pub fn new_entity(&mut self) -> EntityId {
+        if let Some(index) = self.free_entities.pop() {
+            self.alive[index] = true;
+            return EntityId { index, generation: self.generations[index] };
+        }
+        
+        let index = self.entities_count;
+        self.generations.push(0);
+        self.alive.push(true);
+        self.entities_count += 1;
+        EntityId { index, generation: 0 }
+    }
+    
+    /// Returns true if the handle still refers to a spawned entity
+    pub fn is_alive(&self, entity: EntityId) -> bool {
+        entity.index < self.entities_count
+            && self.alive[entity.index]
+            && self.generations[entity.index] == entity.generation
+    }
+    
+    /// Removes every component from the entity and frees its slot for reuse,
+    /// despawning its children along with it.
+    /// Returns false if the handle was already stale.
+    pub fn despawn(&mut self, entity: EntityId) -> bool {
+        if !self.is_alive(entity) {
+            return false;
+        }
+        let children = self.remove_component_from_entity::<Children>(entity);
+        self.remove_parent(entity);
+        // Marked dead before its children go, so nothing below can despawn it a second time
+        self.alive[entity.index] = false;
+        for child in children.into_iter().flat_map(|children| children.0) {
+            self.despawn(child);
+        }
+        
+        let tick = self.increment_change_tick();
+        for (type_id, component_vec) in self.component_vecs.iter_mut() {
+            if component_vec.remove(entity.index) {
+                self.removed.entry(*type_id).or_default().push((entity, tick));
+            }
+        }
+        self.generations[entity.index] = self.generations[entity.index].wrapping_add(1);
+        self.free_entities.push(entity.index);
+        true
+    }
+    
+    // Component storage is looked up by `TypeId`, so the only downcast is the one
+    // that turns the boxed storage back into its concrete type.
+    // When the guard is dropped the lock can be taken again.
+    pub(crate) fn borrow_component_vec_mut<ComponentType: 'static>(
+        &self,
+    ) -> Option<RwLockWriteGuard<'_, SparseSet<ComponentType>>> {
+        schedule::check_access::<ComponentType>(true);
+        let component_vec = self.component_vecs.get(&TypeId::of::<ComponentType>())?;
+        let lock = component_vec
+            .as_any()
+            .downcast_ref::<RwLock<SparseSet<ComponentType>>>()?;
+        let mut guard = write_lock(lock);
+        guard.mark_modified();
+        Some(guard)
+    }
+    
+    pub(crate) fn borrow_component_vec<ComponentType: 'static>(
+        &self,
+    ) -> Option<RwLockReadGuard<'_, SparseSet<ComponentType>>> {
+        schedule::check_access::<ComponentType>(false);
+        let component_vec = self.component_vecs.get(&TypeId::of::<ComponentType>())?;
+        let lock = component_vec
+            .as_any()
+            .downcast_ref::<RwLock<SparseSet<ComponentType>>>()?;
+        Some(read_lock(lock))
+    }
+    
+    pub fn add_component_to_entity<ComponentType: Send + Sync + 'static>(
+        &mut self,
+        entity: EntityId,
+        component: ComponentType,
+    ) {
+        if !self.is_alive(entity) {
+            error!("tried to add a component to despawned entity {:?}", entity);
+            return;
+        }
+        let (_, tick) = schedule::change_ticks(self);
+        // Creates the storage the first time a component type is used
+        let component_vec = self.component_vecs
+            .entry(TypeId::of::<ComponentType>())
+            .or_insert_with(|| Box::new(RwLock::new(SparseSet::<ComponentType>::new())));
+        component_vec
+            .as_any_mut()
+            .downcast_mut::<RwLock<SparseSet<ComponentType>>>()
+            .unwrap()
+            .get_mut()
+            .unwrap()
+            .insert(entity.index, component, tick);
+    }
+    
+    /// Takes a component off an entity and hands it back
+    pub fn remove_component_from_entity<ComponentType: 'static>(
+        &mut self,
+        entity: EntityId,
+    ) -> Option<ComponentType> {
+        if !self.is_alive(entity) {
+            return None;
+        }
+        let type_id = TypeId::of::<ComponentType>();
+        let component = self.component_vecs
+            .get_mut(&type_id)?
+            .as_any_mut()
+            .downcast_mut::<RwLock<SparseSet<ComponentType>>>()?
+            .get_mut()
+            .unwrap()
+            .remove(entity.index)?;
+        let tick = self.increment_change_tick();
+        self.removed.entry(type_id).or_default().push((entity, tick));
+        Some(component)
+    }
+    
+    /// Entities that lost a `T`, either by removal or despawning, since the
+    /// current system last ran
+    pub fn removed<ComponentType: 'static>(&self) -> Vec<EntityId> {
+        let (last_run, _) = schedule::change_ticks(self);
+        match self.removed.get(&TypeId::of::<ComponentType>()) {
+            Some(removed) => removed
+                .iter()
+                .filter(|(_, tick)| *tick > last_run)
+                .map(|(entity, _)| *entity)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+    
+    pub(crate) fn clear_removed_before(&mut self, tick: u64) {
+        for removed in self.removed.values_mut() {
+            removed.retain(|(_, removed_at)| *removed_at > tick);
+        }
+    }
+    
+    /// Registers an event type so it can be sent and read
+    pub fn add_event<E: Send + 'static>(&mut self) {
+        self.events
+            .entry(TypeId::of::<E>())
+            .or_insert_with(|| Box::new(Events::<E>::new()));
+    }
+    
+    fn event_queue<E: 'static>(&self) -> &Events<E> {
+        self.events
+            .get(&TypeId::of::<E>())
+            .and_then(|events| events.as_any().downcast_ref::<Events<E>>())
+            .unwrap_or_else(|| panic!("event {} was never registered with add_event", type_name::<E>()))
+    }
+    
+    /// Sends an event to every system that reads this event type
+    pub fn send<E: 'static>(&self, event: E) {
+        // Stamped with a fresh tick so systems running alongside the sender
+        // read it on their next run instead of missing it
+        let tick = self.increment_change_tick();
+        self.event_queue::<E>().send(tick, event);
+    }
+    
+    /// Events sent since the current system last ran
+    pub fn read<E: Clone + 'static>(&self) -> Vec<E> {
+        let (last_run, this_run) = schedule::change_ticks(self);
+        self.event_queue::<E>().read(last_run, this_run)
+    }
+    
+    pub(crate) fn clear_events_before(&mut self, tick: u64) {
+        for events in self.events.values_mut() {
+            events.clear_before(tick);
+        }
+    }
+    
+    /// Includes a component type in snapshots and prefabs, written under `name`
+    pub fn register_component<T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static>(&mut self, name: &'static str) {
+        self.registry.register::<T>(name);
+    }
+    
+    /// Saves every entity and registered component as bincode
+    pub fn save_snapshot(&self) -> Result<Vec<u8>, bincode::Error> {
+        bincode::DefaultOptions::new().serialize(&SnapshotRef(self))
+    }
+    
+    /// Saves every entity and registered component as readable RON text
+    pub fn save_snapshot_ron(&self) -> Result<String, ron::Error> {
+        ron::ser::to_string_pretty(&SnapshotRef(self), ron::ser::PrettyConfig::default())
+    }
+    
+    /// Replaces every entity and component with the ones from a bincode snapshot.
+    /// The world is left untouched if the snapshot can't be read.
+    pub fn load_snapshot(&mut self, bytes: &[u8]) -> Result<(), bincode::Error> {
+        let snapshot = bincode::DefaultOptions::new().deserialize_seed(SnapshotSeed(&self.registry), bytes)?;
+        snapshot.restore(self);
+        Ok(())
+    }
+    
+    /// Same as `load_snapshot`, for snapshots saved with `save_snapshot_ron`
+    pub fn load_snapshot_ron(&mut self, text: &str) -> Result<(), ron::error::SpannedError> {
+        let snapshot = ron::Options::default().from_str_seed(text, SnapshotSeed(&self.registry))?;
+        snapshot.restore(self);
+        Ok(())
+    }
+    
+    /// Reads a prefab written in RON and stores it under `name`,
+    /// replacing any prefab already using that name
+    pub fn add_prefab(&mut self, name: &str, text: &str) -> Result<(), ron::error::SpannedError> {
+        let prefab = ron::Options::default().from_str_seed(text, PrefabSeed(&self.registry))?;
+        self.resource_mut::<Prefabs>().unwrap().0.insert(name.to_string(), prefab);
+        Ok(())
+    }
+    
+    /// Spawns an entity from a prefab, with its `Coordinates` set to `at`.
+    /// Returns None if there is no prefab with that name.
+    pub fn spawn_prefab(&mut self, name: &str, at: (f64, f64)) -> Option<EntityId> {
+        // Taken out while spawning so components can be added to the world
+        let prefabs = self.remove_resource::<Prefabs>()?;
+        let entity = match prefabs.resolve(name) {
+            Some(components) => {
+                let entity = self.new_entity();
+                for component in components {
+                    component.insert_into(self, entity);
+                }
+                self.add_component_to_entity(entity, Coordinates { coord_x: at.0, coord_y: at.1 });
+                Some(entity)
+            },
+            None => {
+                error!("no prefab named {}", name);
+                None
+            },
+        };
+        self.insert_resource(prefabs);
+        entity
+    }
+    
+    /// Advances the world's change tick and returns the new value
+    pub(crate) fn increment_change_tick(&self) -> u64 {
+        self.change_tick.fetch_add(1, Ordering::Relaxed) + 1
+    }
+    
+    /// Attaches `child` to `parent`, detaching it from any previous parent first.
+    /// Attaching an entity to itself or one of its own descendants is refused.
+    pub fn set_parent(&mut self, child: EntityId, parent: EntityId) {
+        if !self.is_alive(child) || !self.is_alive(parent) {
+            return;
+        }
+        if self.is_ancestor(child, parent) {
+            error!("can't attach {:?} to {:?}, which would loop the hierarchy", child, parent);
+            return;
+        }
+        self.remove_parent(child);
+        self.add_component_to_entity(child, Parent(parent));
+        
+        let mut children = self.borrow_component_vec_mut::<Children>();
+        match children.as_mut().and_then(|children| children.get_mut(parent.index)) {
+            Some(children) => children.0.push(child),
+            None => {
+                drop(children);
+                self.add_component_to_entity(parent, Children(vec![child]));
+            },
+        }
+    }
+    
+    /// Returns true if `ancestor` is `entity` or any entity above it in the hierarchy
+    pub fn is_ancestor(&self, ancestor: EntityId, entity: EntityId) -> bool {
+        let parents = self.borrow_component_vec::<Parent>();
+        let mut current = Some(entity);
+        while let Some(entity) = current {
+            if entity == ancestor {
+                return true;
+            }
+            current = parents.as_ref().and_then(|parents| parents.get(entity.index)).map(|parent| parent.0);
+        }
+        false
+    }
+    
+    /// Detaches an entity from its parent, leaving its coordinates where they are
+    pub fn remove_parent(&mut self, child: EntityId) {
+        let parent = match self.remove_component_from_entity::<Parent>(child) {
+            Some(parent) => parent.0,
+            None => return,
+        };
+        let mut children = self.borrow_component_vec_mut::<Children>();
+        if let Some(siblings) = children.as_mut().and_then(|children| children.get_mut(parent.index)) {
+            siblings.0.retain(|sibling| *sibling != child);
+        }
+    }
+    
+    /// Queue for spawning, despawning and changing components from inside systems
+    pub fn commands(&self) -> &Commands {
+        &self.commands
+    }
+    
+    /// Applies queued commands, including any queued while applying
+    pub fn apply_commands(&mut self) {
+        loop {
+            let commands = self.commands.drain();
+            if commands.is_empty() {
+                break;
+            }
+            for command in commands {
+                command(self);
+            }
+        }
+    }
+    
+    /// Adds a global value to the world, replacing any existing one of the same type
+    pub fn insert_resource<R: Send + Sync + 'static>(&mut self, resource: R) {
+        self.resources.insert(TypeId::of::<R>(), Box::new(RwLock::new(resource)));
+    }
+    
+    /// Removes a global value from the world and hands it back
+    pub fn remove_resource<R: 'static>(&mut self) -> Option<R> {
+        let resource = self.resources.remove(&TypeId::of::<R>())?;
+        Some(resource.downcast::<RwLock<R>>().ok()?.into_inner().unwrap())
+    }
+    
+    // Resources are locked the same way as component vecs,
+    // so borrowing the same one mutably twice will panic.
+    pub fn resource<R: 'static>(&self) -> Option<RwLockReadGuard<'_, R>> {
+        schedule::check_access::<R>(false);
+        let resource = self.resources.get(&TypeId::of::<R>())?;
+        Some(read_lock(resource.downcast_ref::<RwLock<R>>()?))
+    }
+    
+    pub fn resource_mut<R: 'static>(&self) -> Option<RwLockWriteGuard<'_, R>> {
+        schedule::check_access::<R>(true);
+        let resource = self.resources.get(&TypeId::of::<R>())?;
+        Some(write_lock(resource.downcast_ref::<RwLock<R>>()?))
+    }
+    
+    /// Queries every entity that has all of the requested components, e.g.
+    /// `world.query::<(&mut Coordinates, Option<&Collider>)>()`
+    pub fn query<Q: QueryData>(&self) -> Query<'_, Q> {
+        Query::new(self)
+    }
+    
+    /// Same as `query`, narrowed by `With`/`Without` filters
+    pub fn query_filtered<Q: QueryData, F: QueryFilter>(&self) -> Query<'_, Q, F> {
+        Query::new(self)
+    }
+    
+    /// Finds the entity with the given `Name`. If several share it, any one of them may be returned.
+    ///
+    /// Names are looked up in an index that is only rebuilt after `Name` components are
+    /// added, removed or borrowed mutably, so this is cheap to call every tick.
+    /// Systems calling it need to declare that they read `Name`.
+    pub fn find_by_name(&self, name: &str) -> Option<EntityId> {
+        let names = self.borrow_component_vec::<Name>();
+        let version = names.as_ref().map_or(0, |names| names.version());
+        let mut index = self.names.lock().unwrap();
+        
+        if index.version != version {
+            index.version = version;
+            index.entities.clear();
+            for (entity, found) in names.iter().flat_map(|names| names.entities().iter().map(|&entity| (entity, names.get(entity).unwrap()))) {
+                let entity = EntityId { index: entity, generation: self.generations[entity] };
+                index.entities.entry(found.0.clone()).or_insert(entity);
+            }
+        }
+        index.entities.get(name).copied()
+    }
+    
+    fn update_camera(&self) {
+        let mut camera = self.resource_mut::<Camera>().unwrap();
+        let mut players = self.query_filtered::<(&Coordinates,), With<Player>>();
+        
+        if let Some((_, (player,))) = players.iter().next() {
+            camera.update(player);
+        }
+    }
+    
+    /// Updates world movement
+    fn simulate(&self) {
+        let time = self.resource::<Time>().unwrap();
+        let mut spatial = self.resource_mut::<SpatialHash>().unwrap();
+        let mut contacts = self.resource_mut::<TriggerContacts>().unwrap();
+        let mut bodies = self.query::<(&mut Coordinates, &mut Collider)>();
+        let mut collisions = Vec::new();
+        let mut triggers = Vec::new();
+        
+        for entity in self.removed::<Collider>().into_iter().chain(self.removed::<Coordinates>()) {
+            spatial.remove(entity);
+        }
+        physics::simulate_frame(time.delta(), &mut bodies, &mut spatial, &mut contacts, &mut collisions, &mut triggers);
+        for collision in collisions {
+            self.send(collision);
+        }
+        for trigger in triggers {
+            self.send(trigger);
+        }
+    }
+    
+    fn animate(&self) {
+        let time = self.resource::<Time>().unwrap();
+        let images = self.resource::<Images>().unwrap();
+        let mut sprites = self.query::<(&mut Sprite,)>();
+        let mut loops = Vec::new();
+        
+        render::animate_frame(time.delta(), &mut sprites, &images.0, &mut loops);
+        for animation_loop in loops {
+            self.send(animation_loop);
+        }
+    }
+
+    /// Draws into the frame buffer resource, which gets copied to the window on redraw
+    fn draw(&self) {
+        let time = self.resource::<Time>().unwrap();
+        let camera = self.resource::<Camera>().unwrap();
+        let images = self.resource::<Images>().unwrap();
+        let mut frame = self.resource_mut::<FrameBuffer>().unwrap();
+        let mut sprites = self.query::<(&Sprite, &Coordinates)>();
+        
+        render::render_frame(&time.last_updated, &mut sprites, &images.0, &mut frame.0, &camera);
+    }
+    
+    fn draw_grapples(&self) {
+        let camera = self.resource::<Camera>().unwrap();
+        let mouse = self.resource::<MousePosition>().unwrap();
+        let mut frame = self.resource_mut::<FrameBuffer>().unwrap();
+        let mut grapples = self.query::<(&Grapple, &Coordinates, Option<&Collider>)>();
+        
+        render::draw_grapples(&mut grapples, &mouse, &mut frame.0, &camera);
+    }
+}
+
+impl Default for World {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Builds the schedule of built-in engine systems, which game systems can be added to
+pub fn default_schedule() -> Schedule {
+    let mut schedule = Schedule::new();
+    schedule.add_system(Stage::Input, "handle_input", input::handle_input)
+        .reads::<InputMap>()
+        .writes::<FrameInput>()
+        .reads::<Player>()
+        .writes::<Collider>()
+        .writes::<Sprite>();
+    schedule.add_system(Stage::PrePhysics, "camera", World::update_camera)
+        .reads::<Coordinates>()
+        .reads::<Player>()
+        .writes::<Camera>();
+    schedule.add_system(Stage::PrePhysics, "fire_grapples", player::fire_grapples)
+        .after("camera")
+        .reads::<Camera>()
+        .reads::<MousePosition>()
+        .writes::<Grapple>();
+    schedule.add_system(Stage::PrePhysics, "check_health", player::check_health)
+        .reads::<Health>()
+        .reads::<Dead>();
+    schedule.add_system(Stage::PrePhysics, "propagate_transforms", hierarchy::propagate_transforms)
+        .reads::<Parent>()
+        .reads::<Children>()
+        .reads::<LocalOffset>()
+        .writes::<Coordinates>();
+    schedule.add_system(Stage::Physics, "pull_grapples", player::pull_grapples)
+        .before("simulate_frame")
+        .reads::<Time>()
+        .reads::<Grapple>()
+        .reads::<Coordinates>()
+        .writes::<Collider>();
+    schedule.add_system(Stage::Physics, "simulate_frame", World::simulate)
+        .reads::<Time>()
+        .writes::<SpatialHash>()
+        .writes::<TriggerContacts>()
+        .writes::<Coordinates>()
+        .writes::<Collider>();
+    // Runs again so attached entities follow whatever physics just moved
+    schedule.add_system(Stage::Animation, "propagate_transforms_after_physics", hierarchy::propagate_transforms)
+        .reads::<Parent>()
+        .reads::<Children>()
+        .reads::<LocalOffset>()
+        .writes::<Coordinates>();
+    schedule.add_system(Stage::Animation, "animate_sprites", World::animate)
+        .reads::<Time>()
+        .reads::<Images>()
+        .writes::<Sprite>();
+    schedule.add_system(Stage::Render, "render_frame", World::draw)
+        .reads::<Time>()
+        .reads::<Camera>()
+        .reads::<Images>()
+        .reads::<Sprite>()
+        .reads::<Coordinates>()
+        .writes::<FrameBuffer>();
+    schedule.add_system(Stage::Render, "draw_grapples", World::draw_grapples)
+        .after("render_frame")
+        .reads::<Camera>()
+        .reads::<MousePosition>()
+        .reads::<Grapple>()
+        .reads::<Coordinates>()
+        .reads::<Collider>()
+        .writes::<FrameBuffer>();
+    schedule
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Ping(u32);
+
+    #[test]
+    fn despawned_slots_are_reused_with_a_new_generation() {
+        let mut world = World::new();
+        let first = world.new_entity();
+        world.add_component_to_entity(first, Coordinates { coord_x: 1.0, coord_y: 2.0 });
+        assert!(world.despawn(first));
+        assert!(!world.is_alive(first));
+        assert!(world.query::<(&Coordinates,)>().iter().next().is_none());
+
+        let second = world.new_entity();
+        assert_eq!(second, EntityId { index: first.index, generation: first.generation + 1 });
+        // the stale handle doesn't reach the new entity
+        world.add_component_to_entity(first, Coordinates { coord_x: 0.0, coord_y: 0.0 });
+        assert!(world.query::<(&Coordinates,)>().get(second).is_none());
+        assert!(!world.despawn(first));
+        assert!(world.is_alive(second));
+    }
+
+    #[test]
+    fn systems_read_each_event_once() {
+        let mut world = World::new();
+        world.add_event::<Ping>();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut schedule = Schedule::new();
+        let early = seen.clone();
+        schedule.add_system(Stage::Input, "early", move |world: &World| {
+            early.lock().unwrap().extend(world.read::<Ping>().into_iter().map(|ping| ("early", ping)));
+        });
+        let sent = AtomicU64::new(0);
+        schedule.add_system(Stage::Physics, "send", move |world: &World| {
+            if sent.fetch_add(1, Ordering::Relaxed) == 0 {
+                world.send(Ping(7));
+            }
+        });
+        let late = seen.clone();
+        schedule.add_system(Stage::Render, "late", move |world: &World| {
+            late.lock().unwrap().extend(world.read::<Ping>().into_iter().map(|ping| ("late", ping)));
+        });
+        for _ in 0..4 {
+            schedule.run(&mut world);
+        }
+        // systems before the sender see it on the next run, systems after it on the same one
+        assert_eq!(*seen.lock().unwrap(), [("late", Ping(7)), ("early", Ping(7))]);
+    }
+
+    #[test]
+    fn runs_without_assets() {
+        let mut world = World::new();
+        assert!(world.resource::<Images>().unwrap().0.is_empty());
+        let entity = world.new_entity();
+        world.add_component_to_entity(entity, Coordinates { coord_x: 0.0, coord_y: 0.0 });
+        world.add_component_to_entity(entity, Sprite { sprite: String::from("missing"), ..Default::default() });
+        let mut schedule = default_schedule();
+        schedule.run(&mut world);
+        schedule.run(&mut world);
+    }
+
+    #[test]
+    fn finds_entities_by_name() {
+        let mut world = World::new();
+        assert_eq!(world.find_by_name("player"), None);
+        let player = world.new_entity();
+        world.add_component_to_entity(player, Name::new("player"));
+        assert_eq!(world.find_by_name("player"), Some(player));
+        assert_eq!(world.find_by_name("enemy"), None);
+
+        for (_, (mut name,)) in world.query::<(&mut Name,)>().iter() {
+            name.0 = String::from("hero");
+        }
+        assert_eq!(world.find_by_name("player"), None);
+        assert_eq!(world.find_by_name("hero"), Some(player));
+
+        world.despawn(player);
+        assert_eq!(world.find_by_name("hero"), None);
+        let reused = world.new_entity();
+        world.add_component_to_entity(reused, Name::new("hero"));
+        assert_eq!(world.find_by_name("hero"), Some(reused));
+    }
+
+    #[test]
+    fn set_parent_refuses_cycles() {
+        let mut world = World::new();
+        let a = world.new_entity();
+        let b = world.new_entity();
+        let c = world.new_entity();
+        world.set_parent(b, a);
+        world.set_parent(c, b);
+        world.set_parent(a, c);
+        world.set_parent(a, a);
+        assert!(world.query::<(&Parent,)>().get(a).is_none());
+        assert!(world.is_ancestor(a, c));
+        assert!(!world.is_ancestor(c, a));
+    }
+
+    #[test]
+    fn despawn_takes_children_and_frees_each_slot_once() {
+        let mut world = World::new();
+        let a = world.new_entity();
+        let b = world.new_entity();
+        let c = world.new_entity();
+        world.set_parent(b, a);
+        world.set_parent(c, b);
+        assert!(world.despawn(a));
+        assert!(!world.is_alive(b) && !world.is_alive(c));
+        assert!(!world.despawn(a));
+
+        let mut reused: Vec<EntityId> = (0..4).map(|_| world.new_entity()).collect();
+        reused.sort_by_key(|entity| entity.index);
+        reused.dedup();
+        assert_eq!(reused.len(), 4);
+        assert!(reused.iter().all(|entity| entity.index > 2 || entity.generation == 1));
+    }
+}
@@ -1,4 +1,5 @@
-use crate::Image;
+use crate::{Image, World};
+use log::error;
 use std::collections::HashMap;
 use std::fs;
 
@@ -23,3 +24,31 @@ pub fn load_images() -> HashMap<String, Image> {
     } 
     return images;
 }
+
+/// Loads every `.ron` file in a folder as a prefab named after the file.
+/// Needs to run after the game registers its own components.
+pub fn load_prefabs(world: &mut World, folder: &str) {
+    let paths = match fs::read_dir(folder) {
+        Ok(paths) => paths,
+        Err(err) => {
+            error!("couldn't read prefab folder {}: {}", folder, err);
+            return;
+        },
+    };
+
+    for path in paths.flatten().map(|entry| entry.path()) {
+        if path.extension().is_none_or(|extension| extension != "ron") {
+            continue;
+        }
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let loaded = fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| world.add_prefab(&name, &text).map_err(|err| err.to_string()));
+        if let Err(err) = loaded {
+            error!("couldn't load prefab {}: {}", path.display(), err);
+        }
+    }
+    if cfg!(debug_assertions) {
+        println!("Prefabs Loaded!");
+    }
+}